@@ -0,0 +1,349 @@
+/*----------------------------------------------------------------------------------------------------------
+ *  Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/conclave-room-net-rs
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *--------------------------------------------------------------------------------------------------------*/
+//! Datagrams authenticated with HMAC-SHA256
+//!
+//! Join requests and join accepts are authenticated with the shared [`RoomKey`]. Every other
+//! datagram is numbered and uses the [`ConnectionKeys`] of its connection, derived from a random
+//! secret that the server picks for the connection. The secret is sent in the join accept, masked
+//! with a pad derived from the room key, the join nonce and the session token.
+//!
+//! The nonce and the session token are sent in the clear, so the pad only hides the secret from
+//! those without the room key. A room member that can see the traffic of another connection can
+//! unmask its secret, and then forge or decrypt its datagrams, or answer its join request with a
+//! join accept of its own. The connection keys only protect against members that can not see the
+//! traffic, like one that knows a session token from a room info or a log.
+//! With the `encryption` feature the numbered datagrams are encrypted by [`crate::encryption`].
+use std::fmt;
+use std::io;
+
+use flood_rs::{InOctetStream, OutOctetStream};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::datagram::{
+    finish_datagram, without_checksum, ENCRYPTED_FLAG, FLAGS_OFFSET, MAC_FLAG, MAC_SIZE,
+    SEQUENCE_FLAG,
+};
+#[cfg(feature = "encryption")]
+use crate::encryption;
+use crate::{JoinNonce, ReceiveError, SessionToken};
+
+/// Secret shared by the server and every client that is allowed to join the room.
+pub type RoomKey = [u8; 32];
+
+/// Picked at random by the server for every connection, the [`ConnectionKeys`] are derived from it.
+pub(crate) type ConnectionSecret = [u8; 32];
+
+type HmacSha256 = Hmac<Sha256>;
+
+const TO_SERVER_KEY_CONTEXT: &[u8] = b"conclave-room-net to server key";
+const TO_CLIENT_KEY_CONTEXT: &[u8] = b"conclave-room-net to client key";
+const SECRET_MASK_CONTEXT: &[u8] = b"conclave-room-net secret mask";
+
+fn hmac(key: &RoomKey) -> HmacSha256 {
+    HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length")
+}
+
+/// The keys for the numbered datagrams of a joined connection. There is one for each direction,
+/// so the client and the server can both count their sequence numbers from zero.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct ConnectionKeys {
+    pub secret: ConnectionSecret,
+    pub to_server: RoomKey,
+    pub to_client: RoomKey,
+}
+
+impl ConnectionKeys {
+    /// Keys from a new secret from the random source of the operating system.
+    pub fn generate() -> io::Result<Self> {
+        let mut secret = [0u8; 32];
+        getrandom::getrandom(&mut secret)?;
+        Ok(Self::from_secret(secret))
+    }
+
+    pub fn from_secret(secret: ConnectionSecret) -> Self {
+        let derive = |context: &[u8]| -> RoomKey {
+            let mut mac = hmac(&secret);
+            mac.update(context);
+            mac.finalize().into_bytes().into()
+        };
+        Self {
+            secret,
+            to_server: derive(TO_SERVER_KEY_CONTEXT),
+            to_client: derive(TO_CLIENT_KEY_CONTEXT),
+        }
+    }
+}
+
+/// Masks the secret for the join accept that answers `nonce`, or unmasks it again. The mask is
+/// different for every join request and connection, but anyone with the room key that saw the
+/// join accept can calculate it, see the module documentation.
+pub(crate) fn mask_secret(
+    secret: &ConnectionSecret,
+    room_key: &RoomKey,
+    nonce: JoinNonce,
+    session_token: SessionToken,
+) -> ConnectionSecret {
+    let mut mac = hmac(room_key);
+    mac.update(SECRET_MASK_CONTEXT);
+    mac.update(&nonce.to_be_bytes());
+    mac.update(&session_token.to_be_bytes());
+    let mask: [u8; 32] = mac.finalize().into_bytes().into();
+
+    let mut masked = *secret;
+    for (octet, mask_octet) in masked.iter_mut().zip(mask) {
+        *octet ^= mask_octet;
+    }
+    masked
+}
+
+/// Keeps the keys out of logs.
+impl fmt::Debug for ConnectionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionKeys").finish_non_exhaustive()
+    }
+}
+
+fn flags(octets: &[u8]) -> u8 {
+    without_checksum(octets)
+        .get(FLAGS_OFFSET)
+        .copied()
+        .unwrap_or_default()
+}
+
+/// Sets the MAC flag of a complete datagram and inserts the MAC in front of the checksum.
+pub(crate) fn authenticate(octets: &[u8], key: &RoomKey) -> io::Result<Vec<u8>> {
+    let mut stream = OutOctetStream::new();
+    stream.data = without_checksum(octets).to_vec();
+    if let Some(flags) = stream.data.get_mut(FLAGS_OFFSET) {
+        *flags |= MAC_FLAG;
+    }
+
+    let mut mac = hmac(key);
+    mac.update(&stream.data);
+    stream.data.extend_from_slice(&mac.finalize().into_bytes());
+
+    finish_datagram(stream)
+}
+
+/// Verifies the MAC of a complete datagram that has already passed [`crate::datagram::read_datagram`].
+pub(crate) fn verify(octets: &[u8], key: &RoomKey) -> Result<(), ReceiveError> {
+    let authenticated = without_checksum(octets);
+    let has_mac = authenticated
+        .get(FLAGS_OFFSET)
+        .is_some_and(|flags| flags & MAC_FLAG != 0);
+    if !has_mac || authenticated.len() < MAC_SIZE {
+        return Err(ReceiveError::InvalidMac);
+    }
+
+    let (message, tag) = authenticated.split_at(authenticated.len() - MAC_SIZE);
+    let mut mac = hmac(key);
+    mac.update(message);
+    mac.verify_slice(tag).map_err(|_| ReceiveError::InvalidMac)
+}
+
+#[cfg(feature = "encryption")]
+fn seal_numbered(octets: &[u8], key: &RoomKey) -> io::Result<Vec<u8>> {
+    encryption::encrypt(octets, key)
+}
+
+#[cfg(not(feature = "encryption"))]
+fn seal_numbered(octets: &[u8], key: &RoomKey) -> io::Result<Vec<u8>> {
+    authenticate(octets, key)
+}
+
+#[cfg(feature = "encryption")]
+fn open_numbered(
+    octets: &[u8],
+    _body: InOctetStream,
+    key: &RoomKey,
+) -> Result<InOctetStream, ReceiveError> {
+    encryption::decrypt(octets, key)
+}
+
+#[cfg(not(feature = "encryption"))]
+fn open_numbered(
+    octets: &[u8],
+    body: InOctetStream,
+    key: &RoomKey,
+) -> Result<InOctetStream, ReceiveError> {
+    if flags(octets) & ENCRYPTED_FLAG != 0 {
+        return Err(ReceiveError::DecryptionFailed);
+    }
+    verify(octets, key)?;
+    Ok(body)
+}
+
+/// Seals a complete datagram, the same way on both sides. A numbered datagram belongs to a joined
+/// connection and is sealed with `connection_key`, the key for the direction it is sent in. Every
+/// other datagram gets a MAC with the room key.
+pub(crate) fn seal(
+    octets: &[u8],
+    room_key: &RoomKey,
+    connection_key: Option<&RoomKey>,
+) -> io::Result<Vec<u8>> {
+    if flags(octets) & SEQUENCE_FLAG == 0 {
+        return authenticate(octets, room_key);
+    }
+    match connection_key {
+        Some(connection_key) => seal_numbered(octets, connection_key),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a numbered datagram needs a connection key",
+        )),
+    }
+}
+
+/// Opens a complete datagram sealed by [`seal`] and returns the commands. `connection_key` is the
+/// key of the connection the datagram comes from, for the direction it was sent in.
+pub(crate) fn open(
+    octets: &[u8],
+    body: InOctetStream,
+    room_key: &RoomKey,
+    connection_key: Option<&RoomKey>,
+) -> Result<InOctetStream, ReceiveError> {
+    let flags = flags(octets);
+    if flags & SEQUENCE_FLAG == 0 {
+        if flags & ENCRYPTED_FLAG != 0 {
+            return Err(ReceiveError::DecryptionFailed);
+        }
+        verify(octets, room_key)?;
+        return Ok(body);
+    }
+    match connection_key {
+        Some(connection_key) => open_numbered(octets, body, connection_key),
+        None => Err(ReceiveError::InvalidMac),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flood_rs::{InOctetStream, OutOctetStream, ReadOctetStream};
+
+    use crate::auth::{
+        authenticate, mask_secret, open, seal, verify, ConnectionKeys, ConnectionSecret, RoomKey,
+    };
+    use crate::commands::{command_datagram, join_request_datagram};
+    use crate::datagram::{finish_datagram, read_datagram, without_checksum, DatagramHeader};
+    use crate::{ReceiveError, JOIN_REQUEST_COMMAND_TYPE_ID, LEAVE_COMMAND_TYPE_ID};
+
+    const ROOM_KEY: RoomKey = [0x42; 32];
+
+    const SECRET: ConnectionSecret = [0x07; 32];
+
+    fn leave() -> Vec<u8> {
+        let header = DatagramHeader {
+            session_token: Some(7),
+            sequence: Some(0),
+        };
+        command_datagram(LEAVE_COMMAND_TYPE_ID, &header).unwrap()
+    }
+
+    fn join_request() -> Vec<u8> {
+        join_request_datagram(1).unwrap()
+    }
+
+    fn open_command(
+        octets: &[u8],
+        room_key: &RoomKey,
+        connection_key: Option<&RoomKey>,
+    ) -> Result<u8, ReceiveError> {
+        let (_, body) = read_datagram(&mut InOctetStream::new(octets.to_vec()))?;
+        let mut body = open(octets, body, room_key, connection_key)?;
+        Ok(body.read_u8()?)
+    }
+
+    #[test]
+    fn sealed_datagrams_are_opened() {
+        let keys = ConnectionKeys::from_secret(SECRET);
+        let octets = seal(&leave(), &ROOM_KEY, Some(&keys.to_server)).unwrap();
+        assert_eq!(
+            open_command(&octets, &ROOM_KEY, Some(&keys.to_server)).unwrap(),
+            LEAVE_COMMAND_TYPE_ID
+        );
+
+        let octets = seal(&join_request(), &ROOM_KEY, None).unwrap();
+        assert_eq!(
+            open_command(&octets, &ROOM_KEY, None).unwrap(),
+            JOIN_REQUEST_COMMAND_TYPE_ID
+        );
+    }
+
+    #[test]
+    fn numbered_datagrams_need_a_connection_key() {
+        assert!(seal(&leave(), &ROOM_KEY, None).is_err());
+
+        let keys = ConnectionKeys::from_secret(SECRET);
+        let octets = seal(&leave(), &ROOM_KEY, Some(&keys.to_server)).unwrap();
+        assert!(matches!(
+            open_command(&octets, &ROOM_KEY, None),
+            Err(ReceiveError::InvalidMac)
+        ));
+    }
+
+    #[test]
+    fn on_tampered_octet() {
+        let octets = authenticate(&leave(), &ROOM_KEY).unwrap();
+
+        for index in 0..without_checksum(&octets).len() {
+            // An attacker can recalculate the checksum, but not the MAC
+            let mut stream = OutOctetStream::new();
+            stream.data = without_checksum(&octets).to_vec();
+            stream.data[index] ^= 0x01;
+            let tampered = finish_datagram(stream).unwrap();
+
+            let rejected = read_datagram(&mut InOctetStream::new(tampered.clone())).is_err()
+                || matches!(verify(&tampered, &ROOM_KEY), Err(ReceiveError::InvalidMac));
+            assert!(rejected, "tampering at {index} was not detected");
+        }
+    }
+
+    #[test]
+    fn on_wrong_key() {
+        let keys = ConnectionKeys::from_secret(SECRET);
+        let octets = seal(&leave(), &ROOM_KEY, Some(&keys.to_server)).unwrap();
+        for key in [
+            keys.to_client,
+            ConnectionKeys::from_secret([0x08; 32]).to_server,
+        ] {
+            assert!(open_command(&octets, &ROOM_KEY, Some(&key)).is_err());
+        }
+
+        let octets = seal(&join_request(), &ROOM_KEY, None).unwrap();
+        assert!(matches!(
+            open_command(&octets, &[0x43; 32], None),
+            Err(ReceiveError::InvalidMac)
+        ));
+    }
+
+    #[test]
+    fn on_unsealed_datagram() {
+        let keys = ConnectionKeys::from_secret(SECRET);
+        assert!(open_command(&leave(), &ROOM_KEY, Some(&keys.to_server)).is_err());
+        assert!(matches!(
+            open_command(&join_request(), &ROOM_KEY, None),
+            Err(ReceiveError::InvalidMac)
+        ));
+    }
+
+    #[test]
+    fn masked_secret_needs_room_key_and_nonce() {
+        let masked = mask_secret(&SECRET, &ROOM_KEY, 1, 7);
+        assert_ne!(masked, SECRET);
+        assert_eq!(mask_secret(&masked, &ROOM_KEY, 1, 7), SECRET);
+        assert_ne!(mask_secret(&masked, &[0x43; 32], 1, 7), SECRET);
+        assert_ne!(mask_secret(&SECRET, &ROOM_KEY, 2, 7), masked);
+        assert_ne!(mask_secret(&SECRET, &ROOM_KEY, 1, 8), masked);
+    }
+
+    #[test]
+    fn generated_keys_differ() {
+        let first = ConnectionKeys::generate().unwrap();
+        let second = ConnectionKeys::generate().unwrap();
+        assert_ne!(first.secret, second.secret);
+        assert_ne!(first.to_server, first.to_client);
+        assert_eq!(ConnectionKeys::from_secret(first.secret), first);
+    }
+}
@@ -0,0 +1,58 @@
+/*----------------------------------------------------------------------------------------------------------
+ *  Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/conclave-room-net-rs
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *--------------------------------------------------------------------------------------------------------*/
+//! Runs a single conclave room on a UDP port.
+//!
+//! Usage: `conclave-room-server [bind address] [broadcast interval in milliseconds]`
+use std::time::Duration;
+
+use conclave_room_net::server::UdpRoomServer;
+
+const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0:27000";
+const DEFAULT_BROADCAST_INTERVAL_MS: u64 = 100;
+
+fn main() -> std::io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let bind_address = args
+        .next()
+        .unwrap_or_else(|| DEFAULT_BIND_ADDRESS.to_string());
+    let broadcast_interval_ms = match args.next() {
+        Some(arg) => arg.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("broadcast interval '{}' is not a number", arg),
+            )
+        })?,
+        None => DEFAULT_BROADCAST_INTERVAL_MS,
+    };
+    if broadcast_interval_ms == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "broadcast interval must be at least one millisecond",
+        ));
+    }
+
+    let mut server =
+        UdpRoomServer::bind(&bind_address, Duration::from_millis(broadcast_interval_ms))?;
+    println!(
+        "conclave room listening on {}",
+        server.transport().local_addr()?
+    );
+
+    loop {
+        let report = server.wait_and_update()?;
+        for (address, err) in report.rejected {
+            eprintln!("rejected datagram from {}: {}", address, err);
+        }
+        for connection_id in report.dropped {
+            println!("connection {} dropped", connection_id);
+        }
+        for (address, err) in report.send_errors {
+            eprintln!("could not send to {}: {}", address, err);
+        }
+        for err in report.receive_errors {
+            eprintln!("receive failed: {}", err);
+        }
+    }
+}
@@ -0,0 +1,436 @@
+/*----------------------------------------------------------------------------------------------------------
+ *  Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/conclave-room-net-rs
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *--------------------------------------------------------------------------------------------------------*/
+//! Client side of the net layer
+use std::time::Instant;
+
+use conclave_room::{ConnectionIndex, Knowledge, Term};
+use conclave_room_serialize::{ClientInfo, PingCommand, RoomInfoCommand, PING_COMMAND_TYPE_ID};
+use flood_rs::{InOctetStream, OutOctetStream, ReadOctetStream, WriteOctetStream};
+
+#[cfg(feature = "auth")]
+use crate::auth::{self, ConnectionKeys, RoomKey};
+use crate::commands::{
+    command_datagram, join_request_datagram, JOIN_ACCEPT_COMMAND_TYPE_ID, LEAVE_COMMAND_TYPE_ID,
+    PING_ECHO_COMMAND_TYPE_ID, ROOM_INFO_COMMAND_TYPE_ID,
+};
+use crate::datagram::{
+    finish_datagram, read_datagram, read_remaining, write_header, DatagramHeader,
+};
+use crate::session::new_join_nonce;
+use crate::{JoinNonce, ReceiveDatagram, ReceiveError, ReplayWindow, SendError, SessionToken};
+
+/// The values a client reports to the server, encoded the same way as `Room::receive` expects them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientPing {
+    pub term: Term,
+    pub knowledge: Knowledge,
+    pub has_connection_to_leader: bool,
+}
+
+impl ClientPing {
+    /// A ping without a session token, for rooms that are fed through `Room::receive` directly.
+    pub fn to_datagram(&self) -> Result<Vec<u8>, SendError> {
+        self.to_session_datagram(None)
+    }
+
+    /// A ping carrying the session token that was handed out in the join accept.
+    pub fn to_session_datagram(
+        &self,
+        session_token: Option<SessionToken>,
+    ) -> Result<Vec<u8>, SendError> {
+        self.write_datagram(&DatagramHeader::with_session_token(session_token), None)
+    }
+
+    /// Followed by a ping echo command, if `echo` holds a server time and how many milliseconds it
+    /// was held.
+    pub(crate) fn write_datagram(
+        &self,
+        header: &DatagramHeader,
+        echo: Option<(u32, u16)>,
+    ) -> Result<Vec<u8>, SendError> {
+        let ping_command = PingCommand {
+            term: self.term,
+            knowledge: self.knowledge,
+            has_connection_to_leader: self.has_connection_to_leader,
+        };
+
+        let mut stream = OutOctetStream::new();
+
+        write_header(&mut stream, header)?;
+        stream.write_u8(PING_COMMAND_TYPE_ID)?;
+        ping_command.to_octets(&mut stream)?;
+        if let Some((server_time, held_for_ms)) = echo {
+            stream.write_u8(PING_ECHO_COMMAND_TYPE_ID)?;
+            stream.write_u32(server_time)?;
+            stream.write_u16(held_for_ms)?;
+        }
+
+        Ok(finish_datagram(stream)?)
+    }
+}
+
+/// What a client knows about the room, as reported by the room info datagrams from the server.
+#[derive(Debug, Default)]
+pub struct ClientRoomView {
+    pub term: Term,
+    pub leader_index: Option<ConnectionIndex>,
+    pub client_infos: Vec<ClientInfo>,
+    /// Our own connection index, once the server has accepted our join request.
+    pub own_index: Option<ConnectionIndex>,
+    /// Sent along with every datagram after the join request, once the join is accepted.
+    pub session_token: Option<SessionToken>,
+    /// The nonce of the join request that has not been accepted yet.
+    join_nonce: Option<JoinNonce>,
+    /// Must be the same key as the room has, if it has one.
+    #[cfg(feature = "auth")]
+    pub room_key: Option<RoomKey>,
+    /// Unmasked from the join accept, if we have a room key.
+    #[cfg(feature = "auth")]
+    connection_keys: Option<ConnectionKeys>,
+    pub last_received_at: Option<Instant>,
+    /// Sequence numbers already received from the server.
+    pub replay_window: ReplayWindow,
+    next_sequence: u64,
+    /// The server time from the last room info and when it arrived, echoed by every ping.
+    server_time: Option<(u32, Instant)>,
+    has_room_info: bool,
+    term_changed: bool,
+}
+
+impl ClientRoomView {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A view that authenticates its datagrams with `room_key`, see [`crate::NetRoomConfig`].
+    #[cfg(feature = "auth")]
+    pub fn with_room_key(room_key: RoomKey) -> Self {
+        Self {
+            room_key: Some(room_key),
+            ..Self::default()
+        }
+    }
+
+    /// True if the last received room info had a different term than the one before it.
+    pub fn term_changed(&self) -> bool {
+        self.term_changed
+    }
+
+    /// Asks the server to make us a connection of the room. Can be resent until the join is
+    /// accepted, the server answers with the same connection index. Only a join accept for the
+    /// nonce of this request is accepted, once it has arrived the next call starts a new join.
+    pub fn join_request(&mut self) -> Result<Vec<u8>, SendError> {
+        let nonce = match self.join_nonce {
+            Some(nonce) => nonce,
+            None => *self.join_nonce.insert(new_join_nonce()?),
+        };
+        self.seal(join_request_datagram(nonce)?)
+    }
+
+    /// Tells the server that we are leaving, so it does not have to wait for a timeout.
+    pub fn leave(&mut self) -> Result<Vec<u8>, SendError> {
+        let header = self.next_header();
+        let octets = command_datagram(LEAVE_COMMAND_TYPE_ID, &header)?;
+        self.seal(octets)
+    }
+
+    /// A ping datagram reporting the term we last received from the server. Echoes the server
+    /// time from the last room info, so the server can estimate the round trip time.
+    pub fn ping(
+        &mut self,
+        knowledge: Knowledge,
+        has_connection_to_leader: bool,
+        now: Instant,
+    ) -> Result<Vec<u8>, SendError> {
+        let header = self.next_header();
+        let echo = self.server_time.map(|(server_time, received_at)| {
+            let held_for = now.saturating_duration_since(received_at).as_millis();
+            (server_time, u16::try_from(held_for).unwrap_or(u16::MAX))
+        });
+        let octets = ClientPing {
+            term: self.term,
+            knowledge,
+            has_connection_to_leader,
+        }
+        .write_datagram(&header, echo)?;
+        self.seal(octets)
+    }
+
+    /// The header for our next datagram after the join request. Once we have joined, every
+    /// datagram is numbered so the server can drop the ones that are replayed.
+    fn next_header(&mut self) -> DatagramHeader {
+        let Some(session_token) = self.session_token else {
+            return DatagramHeader::default();
+        };
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        DatagramHeader {
+            session_token: Some(session_token),
+            sequence: Some(sequence),
+        }
+    }
+
+    /// Seals a datagram if we have a room key, see [`auth::seal`].
+    fn seal(&self, octets: Vec<u8>) -> Result<Vec<u8>, SendError> {
+        #[cfg(feature = "auth")]
+        if let Some(room_key) = &self.room_key {
+            let connection_key = self.connection_keys.as_ref().map(|keys| &keys.to_server);
+            return Ok(auth::seal(&octets, room_key, connection_key)?);
+        }
+        Ok(octets)
+    }
+
+    /// Opens a datagram from the server if we have a room key, see [`auth::open`].
+    #[cfg_attr(not(feature = "auth"), allow(unused_variables))]
+    fn open(&self, octets: &[u8], body: InOctetStream) -> Result<InOctetStream, ReceiveError> {
+        #[cfg(feature = "auth")]
+        if let Some(room_key) = &self.room_key {
+            let connection_key = self.connection_keys.as_ref().map(|keys| &keys.to_client);
+            return auth::open(octets, body, room_key, connection_key);
+        }
+        Ok(body)
+    }
+
+    fn on_room_info(
+        &mut self,
+        reader: &mut impl ReadOctetStream,
+        now: Instant,
+    ) -> Result<(), ReceiveError> {
+        let room_info = RoomInfoCommand::from_cursor(reader)?;
+        let own_index = if reader.has_reached_end() {
+            None
+        } else {
+            Some(reader.read_u8()?)
+        };
+        if !reader.has_reached_end() {
+            self.server_time = Some((reader.read_u32()?, now));
+        }
+
+        self.term_changed = self.has_room_info && room_info.term != self.term;
+        self.has_room_info = true;
+        self.term = room_info.term;
+        self.leader_index = room_info.leader_index;
+        self.client_infos = room_info.client_infos;
+        if own_index.is_some() {
+            self.own_index = own_index;
+        }
+
+        Ok(())
+    }
+
+    /// Our own client info, if we know our connection index.
+    pub fn own_client_info(&self) -> Option<&ClientInfo> {
+        let own_index = self.own_index?;
+        self.client_infos
+            .iter()
+            .find(|client_info| client_info.connection_index == own_index)
+    }
+}
+
+impl ReceiveDatagram for ClientRoomView {
+    /// The connection index is ignored, since a client is only connected to the server.
+    fn receive(
+        &mut self,
+        _connection_id: ConnectionIndex,
+        now: Instant,
+        reader: &mut impl ReadOctetStream,
+    ) -> Result<(), ReceiveError> {
+        let octets = read_remaining(reader)?;
+        let (header, body) = read_datagram(&mut InOctetStream::new(octets.clone()))?;
+        let mut body = self.open(&octets, body)?;
+        if let Some(sequence) = header.sequence {
+            if !self.replay_window.accept(sequence) {
+                return Err(ReceiveError::Replayed(sequence));
+            }
+        }
+
+        let command_type_id = body.read_u8()?;
+        // Every member knows the room key, so only the join accept may be sealed with it alone
+        #[cfg(feature = "auth")]
+        if self.room_key.is_some()
+            && header.sequence.is_none()
+            && command_type_id != JOIN_ACCEPT_COMMAND_TYPE_ID
+        {
+            return Err(ReceiveError::InvalidMac);
+        }
+        match command_type_id {
+            ROOM_INFO_COMMAND_TYPE_ID => self.on_room_info(&mut body, now)?,
+            JOIN_ACCEPT_COMMAND_TYPE_ID => {
+                let own_index = body.read_u8()?;
+                let session_token = body.read_u64()?;
+                let nonce = body.read_u64()?;
+                if self.join_nonce != Some(nonce) {
+                    return Err(ReceiveError::UnexpectedJoinAccept);
+                }
+                self.join_nonce = None;
+                self.own_index = Some(own_index);
+                if self.session_token != Some(session_token) {
+                    // A new connection on the server, which starts counting from zero again
+                    self.replay_window = ReplayWindow::new();
+                }
+                #[cfg(feature = "auth")]
+                if let Some(room_key) = &self.room_key {
+                    let mut masked_secret = [0u8; 32];
+                    for octet in &mut masked_secret {
+                        *octet = body.read_u8()?;
+                    }
+                    let secret = auth::mask_secret(&masked_secret, room_key, nonce, session_token);
+                    self.connection_keys = Some(ConnectionKeys::from_secret(secret));
+                }
+                self.session_token = Some(session_token);
+            }
+            _ => return Err(ReceiveError::UnknownCommandTypeId(command_type_id)),
+        }
+        if !body.has_reached_end() {
+            return Err(ReceiveError::MalformedField(
+                "trailing octets after command".to_string(),
+            ));
+        }
+        self.last_received_at = Some(now);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use conclave_room::Room;
+    use flood_rs::InOctetStream;
+
+    use crate::client::ClientRoomView;
+    use crate::commands::join_accept_datagram;
+    use crate::{ReceiveDatagram, ReceiveError, SendDatagram};
+
+    #[test]
+    fn receive_room_info() {
+        let mut room = Room::new();
+        let now = Instant::now();
+        room.create_connection(now);
+        let second_connection_id = room.create_connection(now);
+
+        let mut view = ClientRoomView::new();
+        let octets = room.send_to(second_connection_id).unwrap();
+        view.receive(0, now, &mut InOctetStream::new(octets))
+            .unwrap();
+
+        assert_eq!(view.term, room.term);
+        assert_eq!(view.leader_index, room.leader_index);
+        assert_eq!(view.client_infos.len(), 2);
+        assert_eq!(view.own_index, Some(second_connection_id));
+        assert_eq!(
+            view.own_client_info().unwrap().connection_index,
+            second_connection_id
+        );
+        assert!(!view.term_changed());
+
+        room.create_connection(now);
+        room.term += 1;
+        let octets = room.send().unwrap();
+        view.receive(0, now, &mut InOctetStream::new(octets))
+            .unwrap();
+
+        assert!(view.term_changed());
+        assert_eq!(view.client_infos.len(), 3);
+        assert_eq!(view.own_index, Some(second_connection_id));
+    }
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn receive_trailing_octets() {
+        use crate::datagram::DatagramHeader;
+        use crate::room_info_datagram;
+
+        let mut room = Room::new();
+        let connection_id = room.create_connection(Instant::now());
+        let mut octets =
+            room_info_datagram(&room, connection_id, &DatagramHeader::default(), Some(0)).unwrap();
+        octets.push(0x00);
+
+        let mut view = ClientRoomView::new();
+        assert!(matches!(
+            view.receive(0, Instant::now(), &mut InOctetStream::new(octets)),
+            Err(ReceiveError::MalformedField(_))
+        ));
+    }
+
+    #[test]
+    fn receive_join_accept() {
+        let mut room = Room::new();
+        let now = Instant::now();
+        let connection_id = room.create_connection(now);
+
+        let mut view = ClientRoomView::new();
+        view.join_request().unwrap();
+        let nonce = view.join_nonce.unwrap();
+        let octets = join_accept_datagram(connection_id, 0x5e55_1047, nonce, None).unwrap();
+        view.receive(0, now, &mut InOctetStream::new(octets))
+            .unwrap();
+        assert_eq!(view.own_index, Some(connection_id));
+        assert_eq!(view.session_token, Some(0x5e55_1047));
+        assert!(view.client_infos.is_empty());
+
+        room.term = 3;
+        let octets = room.send().unwrap();
+        view.receive(0, now, &mut InOctetStream::new(octets))
+            .unwrap();
+        assert!(!view.term_changed());
+        assert_eq!(
+            view.own_client_info().unwrap().connection_index,
+            connection_id
+        );
+    }
+
+    #[test]
+    fn reject_unexpected_join_accept() {
+        let now = Instant::now();
+        let mut view = ClientRoomView::new();
+        let octets = join_accept_datagram(1, 0x5e55_1047, 0, None).unwrap();
+        assert!(matches!(
+            view.receive(0, now, &mut InOctetStream::new(octets)),
+            Err(ReceiveError::UnexpectedJoinAccept)
+        ));
+
+        view.join_request().unwrap();
+        let nonce = view.join_nonce.unwrap();
+        let octets = join_accept_datagram(1, 0x5e55_1047, nonce.wrapping_add(1), None).unwrap();
+        assert!(matches!(
+            view.receive(0, now, &mut InOctetStream::new(octets)),
+            Err(ReceiveError::UnexpectedJoinAccept)
+        ));
+
+        let octets = join_accept_datagram(1, 0x5e55_1047, nonce, None).unwrap();
+        view.receive(0, now, &mut InOctetStream::new(octets.clone()))
+            .unwrap();
+        assert!(matches!(
+            view.receive(0, now, &mut InOctetStream::new(octets)),
+            Err(ReceiveError::UnexpectedJoinAccept)
+        ));
+        assert_eq!(view.session_token, Some(0x5e55_1047));
+        assert_eq!(view.own_index, Some(1));
+    }
+
+    #[test]
+    fn ping_uses_received_term() {
+        let mut room = Room::new();
+        let now = Instant::now();
+        let connection_id = room.create_connection(now);
+        room.term = 7;
+
+        let mut view = ClientRoomView::new();
+        let octets = room.send_to(connection_id).unwrap();
+        view.receive(0, now, &mut InOctetStream::new(octets))
+            .unwrap();
+
+        assert_eq!(view.term, 7);
+        let octets = view.ping(99, true, now).unwrap();
+        room.receive(connection_id, now, &mut InOctetStream::new(octets))
+            .unwrap();
+
+        let connection = room.connections.get(&connection_id).unwrap();
+        assert_eq!(connection.knowledge, 99);
+    }
+}
@@ -0,0 +1,172 @@
+/*----------------------------------------------------------------------------------------------------------
+ *  Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/conclave-room-net-rs
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *--------------------------------------------------------------------------------------------------------*/
+//! Commands handled by the net layer itself, next to the ping from `conclave-room-serialize`
+use std::io;
+
+use conclave_room::ConnectionIndex;
+use conclave_room_serialize::{PingCommand, PING_COMMAND_TYPE_ID};
+use flood_rs::{OutOctetStream, ReadOctetStream, WriteOctetStream};
+
+use crate::datagram::{finish_datagram, write_header, DatagramHeader};
+use crate::session::{JoinNonce, SessionToken};
+use crate::ReceiveError;
+
+/// Client asks to become a connection of the room, followed by a [`JoinNonce`].
+pub const JOIN_REQUEST_COMMAND_TYPE_ID: u8 = 0x20;
+
+/// Client leaves the room without waiting for the connection to time out. Has no payload.
+pub const LEAVE_COMMAND_TYPE_ID: u8 = 0x21;
+
+/// Server sends the room info, optionally followed by the connection index of the recipient and
+/// the server time in milliseconds (u32).
+pub const ROOM_INFO_COMMAND_TYPE_ID: u8 = 0x22;
+
+/// Server accepts a join request, followed by the connection index and the [`SessionToken`]
+/// assigned to the client, and the [`JoinNonce`] of the join request. If the room has a key, the
+/// masked secret of the connection (32 octets) comes last.
+pub const JOIN_ACCEPT_COMMAND_TYPE_ID: u8 = 0x23;
+
+/// Client echoes the server time from the last room info (u32), followed by how many milliseconds
+/// it held on to it before sending the echo (u16). Lets the server estimate the round trip time.
+pub const PING_ECHO_COMMAND_TYPE_ID: u8 = 0x24;
+
+const _: () = assert!(
+    JOIN_REQUEST_COMMAND_TYPE_ID != PING_COMMAND_TYPE_ID
+        && LEAVE_COMMAND_TYPE_ID != PING_COMMAND_TYPE_ID
+        && PING_ECHO_COMMAND_TYPE_ID != PING_COMMAND_TYPE_ID
+);
+
+/// A command sent from a client to the room.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ClientCommand {
+    Ping(PingCommand),
+    JoinRequest { nonce: JoinNonce },
+    Leave,
+    PingEcho { server_time: u32, held_for_ms: u16 },
+}
+
+fn read_command(reader: &mut impl ReadOctetStream) -> Result<ClientCommand, ReceiveError> {
+    let command_type_id = reader.read_u8()?;
+    match command_type_id {
+        PING_COMMAND_TYPE_ID => Ok(ClientCommand::Ping(PingCommand::from_cursor(reader)?)),
+        JOIN_REQUEST_COMMAND_TYPE_ID => Ok(ClientCommand::JoinRequest {
+            nonce: reader.read_u64()?,
+        }),
+        LEAVE_COMMAND_TYPE_ID => Ok(ClientCommand::Leave),
+        PING_ECHO_COMMAND_TYPE_ID => Ok(ClientCommand::PingEcho {
+            server_time: reader.read_u32()?,
+            held_for_ms: reader.read_u16()?,
+        }),
+        _ => Err(ReceiveError::UnknownCommandTypeId(command_type_id)),
+    }
+}
+
+/// Reads commands until the stream is exhausted. Nothing is returned if any of them is truncated or
+/// malformed, so a datagram is either handled completely or not at all.
+pub(crate) fn read_commands(
+    reader: &mut impl ReadOctetStream,
+) -> Result<Vec<ClientCommand>, ReceiveError> {
+    let mut commands = vec![read_command(reader)?];
+    while !reader.has_reached_end() {
+        commands.push(read_command(reader)?);
+    }
+    Ok(commands)
+}
+
+/// A datagram holding a single command without payload.
+pub(crate) fn command_datagram(
+    command_type_id: u8,
+    header: &DatagramHeader,
+) -> io::Result<Vec<u8>> {
+    let mut stream = OutOctetStream::new();
+
+    write_header(&mut stream, header)?;
+    stream.write_u8(command_type_id)?;
+
+    finish_datagram(stream)
+}
+
+pub(crate) fn join_request_datagram(nonce: JoinNonce) -> io::Result<Vec<u8>> {
+    let mut stream = OutOctetStream::new();
+
+    write_header(&mut stream, &DatagramHeader::default())?;
+    stream.write_u8(JOIN_REQUEST_COMMAND_TYPE_ID)?;
+    stream.write_u64(nonce)?;
+
+    finish_datagram(stream)
+}
+
+pub(crate) fn join_accept_datagram(
+    connection_id: ConnectionIndex,
+    session_token: SessionToken,
+    nonce: JoinNonce,
+    masked_secret: Option<&[u8; 32]>,
+) -> io::Result<Vec<u8>> {
+    let mut stream = OutOctetStream::new();
+
+    write_header(&mut stream, &DatagramHeader::default())?;
+    stream.write_u8(JOIN_ACCEPT_COMMAND_TYPE_ID)?;
+    stream.write_u8(connection_id)?;
+    stream.write_u64(session_token)?;
+    stream.write_u64(nonce)?;
+    if let Some(masked_secret) = masked_secret {
+        stream.data.extend_from_slice(masked_secret);
+    }
+
+    finish_datagram(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use flood_rs::InOctetStream;
+
+    use crate::commands::{
+        read_commands, ClientCommand, JOIN_REQUEST_COMMAND_TYPE_ID, LEAVE_COMMAND_TYPE_ID,
+        PING_ECHO_COMMAND_TYPE_ID,
+    };
+
+    #[test]
+    fn read_join_and_leave() {
+        let mut reader = InOctetStream::new(vec![
+            JOIN_REQUEST_COMMAND_TYPE_ID,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x01,
+            0x02,
+            LEAVE_COMMAND_TYPE_ID,
+        ]);
+        assert_eq!(
+            read_commands(&mut reader).unwrap(),
+            vec![
+                ClientCommand::JoinRequest { nonce: 0x0102 },
+                ClientCommand::Leave
+            ]
+        );
+    }
+
+    #[test]
+    fn read_ping_echo() {
+        let mut reader = InOctetStream::new(vec![
+            PING_ECHO_COMMAND_TYPE_ID,
+            0x00,
+            0x01,
+            0x02,
+            0x03,
+            0x00,
+            0x2a,
+        ]);
+        assert_eq!(
+            read_commands(&mut reader).unwrap(),
+            vec![ClientCommand::PingEcho {
+                server_time: 0x0001_0203,
+                held_for_ms: 42,
+            }]
+        );
+    }
+}
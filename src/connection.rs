@@ -0,0 +1,217 @@
+/*----------------------------------------------------------------------------------------------------------
+ *  Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/conclave-room-net-rs
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *--------------------------------------------------------------------------------------------------------*/
+//! Per-peer state kept by the net layer
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use conclave_room::ConnectionIndex;
+
+#[cfg(feature = "auth")]
+use crate::auth::ConnectionKeys;
+use crate::{ReplayWindow, SessionToken, TokenBucket};
+
+/// Traffic counters for a single connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    /// When the last accepted datagram was received, `None` before the first one.
+    pub last_received_at: Option<Instant>,
+    /// Datagrams from the peer that were corrupted, malformed or failed authentication.
+    pub decode_errors: u64,
+    /// Smoothed round trip time, estimated from the ping echoes of the peer. Relies on the hold
+    /// time the peer reports, see [`NetworkConnection::on_ping_echo`].
+    pub rtt: Option<Duration>,
+    /// Datagrams dropped because their sequence number was stale or already received.
+    pub packets_replayed: u64,
+    /// Datagrams dropped because the peer exceeded the rate limit of the room.
+    pub packets_rate_limited: u64,
+    /// Datagrams for the peer that the transport failed to send.
+    pub send_errors: u64,
+}
+
+/// A peer of the room. The [`conclave_room::Room`] itself is shared by all connections and is
+/// owned by whoever owns the connections, the connection only refers to it by `id`.
+#[derive(Debug)]
+pub struct NetworkConnection<A> {
+    pub id: ConnectionIndex,
+    pub address: A,
+    pub stats: ConnectionStats,
+    /// Must be present in every datagram from the peer, except for join requests.
+    pub session_token: SessionToken,
+    created_at: Instant,
+    /// Sequence numbers already received from the peer.
+    pub replay_window: ReplayWindow,
+    /// Tokens left for datagrams from the peer, see [`crate::NetRoomConfig::rate_limit`].
+    pub rate_limiter: TokenBucket,
+    /// Seal the numbered datagrams in both directions, if the room has a key. Generated by
+    /// [`crate::ConnectionRegistry::get_or_create`].
+    #[cfg(feature = "auth")]
+    pub(crate) keys: Option<ConnectionKeys>,
+    next_sequence: u64,
+    send_queue: VecDeque<Vec<u8>>,
+}
+
+impl<A> NetworkConnection<A> {
+    pub fn new(id: ConnectionIndex, address: A, session_token: SessionToken, now: Instant) -> Self {
+        Self {
+            id,
+            address,
+            stats: ConnectionStats::default(),
+            session_token,
+            created_at: now,
+            replay_window: ReplayWindow::new(),
+            rate_limiter: TokenBucket::new(),
+            #[cfg(feature = "auth")]
+            keys: None,
+            next_sequence: 0,
+            send_queue: VecDeque::new(),
+        }
+    }
+
+    #[cfg(feature = "auth")]
+    pub(crate) fn with_keys(self, keys: ConnectionKeys) -> Self {
+        Self {
+            keys: Some(keys),
+            ..self
+        }
+    }
+
+    /// The sequence number for the next numbered datagram to the peer.
+    pub(crate) fn next_sequence(&mut self) -> u64 {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        sequence
+    }
+
+    /// Should be called for every datagram accepted from the peer.
+    pub fn on_received(&mut self, octet_count: usize, now: Instant) {
+        self.stats.packets_received += 1;
+        self.stats.bytes_received += octet_count as u64;
+        self.stats.last_received_at = Some(now);
+    }
+
+    /// How long nothing has been received from the peer, counting from the creation of the
+    /// connection until the first datagram arrives.
+    pub fn silent_for(&self, now: Instant) -> Duration {
+        let last_received_at = self.stats.last_received_at.unwrap_or(self.created_at);
+        now.saturating_duration_since(last_received_at)
+    }
+
+    /// Milliseconds since the connection was created, wrapping around after about 49 days. Sent
+    /// along with the room info, so the peer can echo it.
+    pub fn server_time(&self, now: Instant) -> u32 {
+        now.saturating_duration_since(self.created_at).as_millis() as u32
+    }
+
+    /// Updates the round trip time with a `server_time` that the peer echoed after holding on to it
+    /// for `held_for`. Echoes from the future are ignored. `held_for` is reported by the peer and
+    /// can not be checked, it is only clamped to the time that has passed, so the round trip time
+    /// is as trustworthy as the peer.
+    pub fn on_ping_echo(&mut self, server_time: u32, held_for: Duration, now: Instant) {
+        let elapsed = self.server_time(now).wrapping_sub(server_time);
+        if elapsed > u32::MAX / 2 {
+            return;
+        }
+        let elapsed = Duration::from_millis(u64::from(elapsed));
+        let rtt = elapsed - held_for.min(elapsed);
+        self.stats.rtt = Some(match self.stats.rtt {
+            Some(smoothed) => (smoothed * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+
+    /// Queues a datagram to be sent to the peer.
+    pub fn queue(&mut self, datagram: Vec<u8>) {
+        self.send_queue.push_back(datagram);
+    }
+
+    pub fn queued_count(&self) -> usize {
+        self.send_queue.len()
+    }
+
+    /// Takes the next queued datagram, counting it as sent.
+    pub fn pop_outgoing(&mut self) -> Option<Vec<u8>> {
+        let datagram = self.send_queue.pop_front()?;
+        self.stats.packets_sent += 1;
+        self.stats.bytes_sent += datagram.len() as u64;
+        Some(datagram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::connection::{ConnectionStats, NetworkConnection};
+
+    #[test]
+    fn send_queue_updates_stats() {
+        let now = Instant::now();
+        let mut connection = NetworkConnection::new(3, "peer", 0, now);
+        let later = now + Duration::from_secs(2);
+        assert_eq!(connection.silent_for(later), Duration::from_secs(2));
+        connection.on_received(12, now + Duration::from_secs(1));
+        assert_eq!(connection.silent_for(later), Duration::from_secs(1));
+        connection.queue(vec![0x01, 0x02]);
+        connection.queue(vec![0x03]);
+        assert_eq!(connection.queued_count(), 2);
+
+        assert_eq!(connection.pop_outgoing(), Some(vec![0x01, 0x02]));
+        assert_eq!(connection.pop_outgoing(), Some(vec![0x03]));
+        assert_eq!(connection.pop_outgoing(), None);
+
+        assert_eq!(
+            connection.stats,
+            ConnectionStats {
+                packets_received: 1,
+                bytes_received: 12,
+                packets_sent: 2,
+                bytes_sent: 3,
+                last_received_at: Some(now + Duration::from_secs(1)),
+                decode_errors: 0,
+                rtt: None,
+                packets_replayed: 0,
+                packets_rate_limited: 0,
+                send_errors: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn ping_echo_updates_rtt() {
+        let created_at = Instant::now();
+        let mut connection = NetworkConnection::new(3, "peer", 0, created_at);
+        let sent_at = created_at + Duration::from_millis(1000);
+        let server_time = connection.server_time(sent_at);
+        assert_eq!(server_time, 1000);
+
+        connection.on_ping_echo(
+            server_time,
+            Duration::from_millis(20),
+            sent_at + Duration::from_millis(100),
+        );
+        assert_eq!(connection.stats.rtt, Some(Duration::from_millis(80)));
+
+        connection.on_ping_echo(
+            server_time,
+            Duration::from_millis(0),
+            sent_at + Duration::from_millis(160),
+        );
+        assert_eq!(connection.stats.rtt, Some(Duration::from_millis(90)));
+
+        connection.on_ping_echo(server_time + 1000, Duration::ZERO, sent_at);
+        assert_eq!(connection.stats.rtt, Some(Duration::from_millis(90)));
+
+        connection.on_ping_echo(
+            server_time,
+            Duration::from_millis(u64::from(u16::MAX)),
+            sent_at + Duration::from_millis(10),
+        );
+        assert_eq!(connection.stats.rtt, Some(Duration::from_micros(78_750)));
+    }
+}
@@ -0,0 +1,355 @@
+/*----------------------------------------------------------------------------------------------------------
+ *  Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/conclave-room-net-rs
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *--------------------------------------------------------------------------------------------------------*/
+//! Header that starts every datagram and the optional MAC and checksum that end it
+use std::io;
+
+use flood_rs::{InOctetStream, OutOctetStream, ReadOctetStream, WriteOctetStream};
+
+use crate::session::SessionToken;
+use crate::ReceiveError;
+
+/// Marks a datagram as belonging to the conclave room protocol ("CR").
+pub const DATAGRAM_MAGIC: u16 = 0x4352;
+
+/// Bumped whenever the layout of a datagram changes.
+pub const PROTOCOL_VERSION: u8 = 8;
+
+/// Header flag telling that the datagram ends with a CRC32 of everything before it.
+pub const CHECKSUM_FLAG: u8 = 0x01;
+
+/// Header flag telling that the flags are followed by the [`SessionToken`] of the sender.
+pub const SESSION_TOKEN_FLAG: u8 = 0x02;
+
+/// Header flag telling that the body is followed by an HMAC-SHA256 of everything before it.
+pub const MAC_FLAG: u8 = 0x04;
+
+/// Header flag telling that the header ends with the sequence number of the datagram.
+pub const SEQUENCE_FLAG: u8 = 0x08;
+
+/// Header flag telling that the body is encrypted and authenticated with ChaCha20-Poly1305.
+pub const ENCRYPTED_FLAG: u8 = 0x10;
+
+const KNOWN_FLAGS: u8 =
+    CHECKSUM_FLAG | SESSION_TOKEN_FLAG | MAC_FLAG | SEQUENCE_FLAG | ENCRYPTED_FLAG;
+
+#[cfg(feature = "auth")]
+pub(crate) const FLAGS_OFFSET: usize = 3;
+
+const CHECKSUM_SIZE: usize = 4;
+
+pub(crate) const MAC_SIZE: usize = 32;
+
+/// The optional fields that follow the flags.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DatagramHeader {
+    /// Set by clients that have joined, never by the server.
+    pub session_token: Option<SessionToken>,
+    /// Increases by one for every datagram a joined client sends, and for every room info the server
+    /// sends to it.
+    pub sequence: Option<u64>,
+}
+
+impl DatagramHeader {
+    pub fn with_session_token(session_token: Option<SessionToken>) -> Self {
+        Self {
+            session_token,
+            sequence: None,
+        }
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if cfg!(feature = "checksum") {
+            flags |= CHECKSUM_FLAG;
+        }
+        if self.session_token.is_some() {
+            flags |= SESSION_TOKEN_FLAG;
+        }
+        if self.sequence.is_some() {
+            flags |= SEQUENCE_FLAG;
+        }
+        flags
+    }
+
+    pub(crate) fn write_octets(
+        &self,
+        flags: u8,
+        stream: &mut impl WriteOctetStream,
+    ) -> io::Result<()> {
+        stream.write_u16(DATAGRAM_MAGIC)?;
+        stream.write_u8(PROTOCOL_VERSION)?;
+        stream.write_u8(flags)?;
+        if let Some(session_token) = self.session_token {
+            stream.write_u64(session_token)?;
+        }
+        if let Some(sequence) = self.sequence {
+            stream.write_u64(sequence)?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn write_header(
+    stream: &mut impl WriteOctetStream,
+    header: &DatagramHeader,
+) -> io::Result<()> {
+    header.write_octets(header.flags(), stream)
+}
+
+/// Completes a datagram started with [`write_header`], appending the checksum if enabled.
+pub(crate) fn finish_datagram(mut stream: OutOctetStream) -> io::Result<Vec<u8>> {
+    if cfg!(feature = "checksum") {
+        let checksum = crc32fast::hash(&stream.data);
+        stream.write_u32(checksum)?;
+    }
+    Ok(stream.data)
+}
+
+/// Everything in a complete datagram except the checksum, which is the part covered by the MAC.
+#[cfg(feature = "auth")]
+pub(crate) fn without_checksum(octets: &[u8]) -> &[u8] {
+    let has_checksum = octets
+        .get(FLAGS_OFFSET)
+        .is_some_and(|flags| flags & CHECKSUM_FLAG != 0);
+    if has_checksum && octets.len() >= CHECKSUM_SIZE {
+        &octets[..octets.len() - CHECKSUM_SIZE]
+    } else {
+        octets
+    }
+}
+
+/// The size of a header with `flags`, which is where the body starts.
+#[cfg(feature = "encryption")]
+pub(crate) fn header_size(flags: u8) -> usize {
+    let mut size = 4;
+    if flags & SESSION_TOKEN_FLAG != 0 {
+        size += 8;
+    }
+    if flags & SEQUENCE_FLAG != 0 {
+        size += 8;
+    }
+    size
+}
+
+pub(crate) fn read_header(
+    reader: &mut impl ReadOctetStream,
+) -> Result<(u8, DatagramHeader), ReceiveError> {
+    let magic = reader.read_u16()?;
+    if magic != DATAGRAM_MAGIC {
+        return Err(ReceiveError::InvalidMagic(magic));
+    }
+
+    let version = reader.read_u8()?;
+    if version != PROTOCOL_VERSION {
+        return Err(ReceiveError::VersionMismatch {
+            expected: PROTOCOL_VERSION,
+            received: version,
+        });
+    }
+
+    let flags = reader.read_u8()?;
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(ReceiveError::MalformedField(format!(
+            "unknown header flags {:#04x}",
+            flags
+        )));
+    }
+
+    let session_token = if flags & SESSION_TOKEN_FLAG != 0 {
+        Some(reader.read_u64()?)
+    } else {
+        None
+    };
+    let sequence = if flags & SEQUENCE_FLAG != 0 {
+        Some(reader.read_u64()?)
+    } else {
+        None
+    };
+
+    Ok((
+        flags,
+        DatagramHeader {
+            session_token,
+            sequence,
+        },
+    ))
+}
+
+pub(crate) fn read_remaining(reader: &mut impl ReadOctetStream) -> io::Result<Vec<u8>> {
+    let mut octets = Vec::new();
+    while !reader.has_reached_end() {
+        octets.push(reader.read_u8()?);
+    }
+    Ok(octets)
+}
+
+/// Validates the header (and checksum, which is required with the `checksum` feature) and returns it together with a stream over the
+/// commands. A MAC is only skipped here and an encrypted body is returned as is, both are handled by
+/// whoever knows the key.
+pub(crate) fn read_datagram(
+    reader: &mut impl ReadOctetStream,
+) -> Result<(DatagramHeader, InOctetStream), ReceiveError> {
+    let (_, header, body) = read_flagged_datagram(reader)?;
+    Ok((header, body))
+}
+
+/// Like [`read_datagram`], for receivers that keep no keys or sessions. A datagram with a MAC,
+/// an encrypted body or a session token is rejected, since none of them can be checked.
+pub(crate) fn read_unsealed_datagram(
+    reader: &mut impl ReadOctetStream,
+) -> Result<InOctetStream, ReceiveError> {
+    let (flags, _, body) = read_flagged_datagram(reader)?;
+    if flags & ENCRYPTED_FLAG != 0 {
+        return Err(ReceiveError::DecryptionFailed);
+    }
+    if flags & MAC_FLAG != 0 {
+        return Err(ReceiveError::InvalidMac);
+    }
+    if flags & SESSION_TOKEN_FLAG != 0 {
+        return Err(ReceiveError::InvalidSessionToken);
+    }
+    Ok(body)
+}
+
+fn read_flagged_datagram(
+    reader: &mut impl ReadOctetStream,
+) -> Result<(u8, DatagramHeader, InOctetStream), ReceiveError> {
+    let (flags, header) = read_header(reader)?;
+    let mut body = read_remaining(reader)?;
+
+    // The flags are not covered by a checksum that they switch off
+    if cfg!(feature = "checksum") && flags & CHECKSUM_FLAG == 0 {
+        return Err(ReceiveError::MissingChecksum);
+    }
+    if flags & CHECKSUM_FLAG != 0 {
+        if body.len() < CHECKSUM_SIZE {
+            return Err(ReceiveError::Truncated);
+        }
+        let checksum_octets = body.split_off(body.len() - CHECKSUM_SIZE);
+        let expected = InOctetStream::new(checksum_octets).read_u32()?;
+
+        let mut hasher_input = OutOctetStream::new();
+        header.write_octets(flags, &mut hasher_input)?;
+        hasher_input.data.extend_from_slice(&body);
+        let calculated = crc32fast::hash(&hasher_input.data);
+
+        if calculated != expected {
+            return Err(ReceiveError::ChecksumMismatch {
+                expected,
+                calculated,
+            });
+        }
+    }
+
+    if flags & MAC_FLAG != 0 {
+        if body.len() < MAC_SIZE {
+            return Err(ReceiveError::Truncated);
+        }
+        body.truncate(body.len() - MAC_SIZE);
+    }
+
+    Ok((flags, header, InOctetStream::new(body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use flood_rs::{InOctetStream, OutOctetStream, ReadOctetStream, WriteOctetStream};
+
+    use crate::datagram::{
+        finish_datagram, read_datagram, write_header, DatagramHeader, CHECKSUM_FLAG,
+        DATAGRAM_MAGIC, PROTOCOL_VERSION,
+    };
+    use crate::ReceiveError;
+
+    fn checksum_datagram(body: &[u8]) -> Vec<u8> {
+        let mut octets = DATAGRAM_MAGIC.to_be_bytes().to_vec();
+        octets.push(PROTOCOL_VERSION);
+        octets.push(CHECKSUM_FLAG);
+        octets.extend_from_slice(body);
+        let checksum = crc32fast::hash(&octets);
+        octets.extend_from_slice(&checksum.to_be_bytes());
+        octets
+    }
+
+    #[test]
+    fn check_checksum() {
+        let mut reader = InOctetStream::new(checksum_datagram(&[0x01, 0x02, 0x03]));
+        let (header, mut body) = read_datagram(&mut reader).unwrap();
+
+        assert_eq!(header.session_token, None);
+
+        assert_eq!(body.read_u8().unwrap(), 0x01);
+        assert_eq!(body.read_u8().unwrap(), 0x02);
+        assert_eq!(body.read_u8().unwrap(), 0x03);
+        assert!(body.has_reached_end());
+    }
+
+    #[test]
+    fn check_header_fields() {
+        let header = DatagramHeader {
+            session_token: Some(0x0123_4567_89ab_cdef),
+            sequence: Some(9),
+        };
+        let mut stream = OutOctetStream::new();
+        write_header(&mut stream, &header).unwrap();
+        stream.write_u8(0x01).unwrap();
+        let octets = finish_datagram(stream).unwrap();
+
+        let (received_header, mut body) = read_datagram(&mut InOctetStream::new(octets)).unwrap();
+        assert_eq!(received_header, header);
+        assert_eq!(body.read_u8().unwrap(), 0x01);
+        assert!(body.has_reached_end());
+    }
+
+    #[test]
+    fn on_corrupted_octet() {
+        let octets = checksum_datagram(&[0x01, 0x02, 0x03]);
+        for index in 3..octets.len() {
+            let mut corrupted = octets.clone();
+            corrupted[index] ^= 0x10;
+            let mut reader = InOctetStream::new(corrupted);
+            assert!(
+                matches!(
+                    read_datagram(&mut reader),
+                    Err(ReceiveError::ChecksumMismatch { .. })
+                ),
+                "corruption at {index} was not detected"
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "checksum")]
+    fn on_cleared_checksum_flag() {
+        let mut octets = checksum_datagram(&[0x21]);
+        octets[3] &= !CHECKSUM_FLAG;
+        let mut reader = InOctetStream::new(octets);
+        assert!(matches!(
+            read_datagram(&mut reader),
+            Err(ReceiveError::MissingChecksum)
+        ));
+    }
+
+    #[test]
+    fn on_missing_checksum() {
+        let octets = checksum_datagram(&[]);
+        let mut reader = InOctetStream::new(octets[..octets.len() - 1].to_vec());
+        assert!(matches!(
+            read_datagram(&mut reader),
+            Err(ReceiveError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn on_unknown_flags() {
+        let mut octets = checksum_datagram(&[]);
+        octets[3] = 0x80;
+        let mut reader = InOctetStream::new(octets);
+        assert!(matches!(
+            read_datagram(&mut reader),
+            Err(ReceiveError::MalformedField(_))
+        ));
+    }
+}
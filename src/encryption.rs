@@ -0,0 +1,200 @@
+/*----------------------------------------------------------------------------------------------------------
+ *  Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/conclave-room-net-rs
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *--------------------------------------------------------------------------------------------------------*/
+//! Datagrams encrypted with ChaCha20-Poly1305
+//!
+//! Only the numbered datagrams of a joined connection are encrypted, with the key that
+//! [`crate::auth`] would use for their MAC. The nonce is the sequence number in the header, which
+//! is never repeated for a key since each direction has its own key. The header is authenticated
+//! as associated data. Like the MAC, it keeps the datagrams from those without the room key, and
+//! from room members that can not see the join accept of the connection.
+use std::io;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use flood_rs::{InOctetStream, OutOctetStream};
+
+use crate::auth::RoomKey;
+use crate::datagram::{
+    finish_datagram, header_size, read_header, read_remaining, without_checksum, ENCRYPTED_FLAG,
+    MAC_FLAG,
+};
+use crate::ReceiveError;
+
+fn nonce(sequence: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&sequence.to_be_bytes());
+    nonce
+}
+
+fn cipher(key: &RoomKey) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+}
+
+/// Encrypts the body of a complete, numbered datagram.
+/// A sequence number must never be used twice with the same key.
+pub(crate) fn encrypt(octets: &[u8], key: &RoomKey) -> io::Result<Vec<u8>> {
+    let mut reader = InOctetStream::new(without_checksum(octets).to_vec());
+    let (flags, header) = read_header(&mut reader)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+    let Some(sequence) = header.sequence else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only numbered datagrams can be encrypted",
+        ));
+    };
+    let body = read_remaining(&mut reader)?;
+
+    let flags = (flags | ENCRYPTED_FLAG) & !MAC_FLAG;
+    let mut stream = OutOctetStream::new();
+    header.write_octets(flags, &mut stream)?;
+
+    let ciphertext = cipher(key)
+        .encrypt(
+            Nonce::from_slice(&nonce(sequence)),
+            Payload {
+                msg: &body,
+                aad: &stream.data,
+            },
+        )
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "encryption failed"))?;
+    stream.data.extend_from_slice(&ciphertext);
+
+    finish_datagram(stream)
+}
+
+/// Decrypts the body of a complete datagram that has already passed
+/// [`crate::datagram::read_datagram`], and returns a stream over the commands.
+pub(crate) fn decrypt(octets: &[u8], key: &RoomKey) -> Result<InOctetStream, ReceiveError> {
+    let authenticated = without_checksum(octets);
+    let (flags, header) = read_header(&mut InOctetStream::new(authenticated.to_vec()))?;
+    if flags & ENCRYPTED_FLAG == 0 {
+        return Err(ReceiveError::DecryptionFailed);
+    }
+    let Some(sequence) = header.sequence else {
+        return Err(ReceiveError::DecryptionFailed);
+    };
+
+    let (associated_data, ciphertext) = authenticated.split_at(header_size(flags));
+    let body = cipher(key)
+        .decrypt(
+            Nonce::from_slice(&nonce(sequence)),
+            Payload {
+                msg: ciphertext,
+                aad: associated_data,
+            },
+        )
+        .map_err(|_| ReceiveError::DecryptionFailed)?;
+
+    Ok(InOctetStream::new(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use flood_rs::{InOctetStream, OutOctetStream, ReadOctetStream};
+
+    use crate::auth::{ConnectionKeys, ConnectionSecret, RoomKey};
+    use crate::datagram::{finish_datagram, read_datagram, without_checksum, DatagramHeader};
+    use crate::encryption::{decrypt, encrypt};
+    use crate::{ClientPing, ReceiveError};
+
+    const SECRET: ConnectionSecret = [0x07; 32];
+
+    const KNOWLEDGE: u64 = 0x0123_4567_89ab_cdef;
+
+    fn ping(sequence: Option<u64>) -> Vec<u8> {
+        let header = DatagramHeader {
+            session_token: Some(7),
+            sequence,
+        };
+        ClientPing {
+            term: 1,
+            knowledge: KNOWLEDGE,
+            has_connection_to_leader: false,
+        }
+        .write_datagram(&header, None)
+        .unwrap()
+    }
+
+    fn key() -> RoomKey {
+        ConnectionKeys::from_secret(SECRET).to_server
+    }
+
+    fn open(octets: &[u8], key: &RoomKey) -> Result<Vec<u8>, ReceiveError> {
+        read_datagram(&mut InOctetStream::new(octets.to_vec()))?;
+        let mut body = decrypt(octets, key)?;
+        let mut plaintext = Vec::new();
+        while !body.has_reached_end() {
+            plaintext.push(body.read_u8()?);
+        }
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn encrypt_and_decrypt() {
+        let octets = encrypt(&ping(Some(3)), &key()).unwrap();
+
+        let (header, _) = read_datagram(&mut InOctetStream::new(octets.clone())).unwrap();
+        assert_eq!(header.session_token, Some(7));
+        assert_eq!(header.sequence, Some(3));
+
+        let (_, mut plaintext) = read_datagram(&mut InOctetStream::new(ping(Some(3)))).unwrap();
+        let mut expected = Vec::new();
+        while !plaintext.has_reached_end() {
+            expected.push(plaintext.read_u8().unwrap());
+        }
+        assert_eq!(open(&octets, &key()).unwrap(), expected);
+    }
+
+    #[test]
+    fn knowledge_is_not_readable() {
+        let octets = encrypt(&ping(Some(0)), &key()).unwrap();
+
+        let knowledge_octets = KNOWLEDGE.to_be_bytes();
+        assert!(!octets
+            .windows(knowledge_octets.len())
+            .any(|window| window == knowledge_octets));
+    }
+
+    #[test]
+    fn on_tampered_octet() {
+        let octets = encrypt(&ping(Some(0)), &key()).unwrap();
+
+        for index in 0..without_checksum(&octets).len() {
+            let mut stream = OutOctetStream::new();
+            stream.data = without_checksum(&octets).to_vec();
+            stream.data[index] ^= 0x01;
+            let tampered = finish_datagram(stream).unwrap();
+
+            assert!(
+                open(&tampered, &key()).is_err(),
+                "tampering at {index} was not detected"
+            );
+        }
+    }
+
+    #[test]
+    fn on_wrong_key() {
+        let octets = encrypt(&ping(Some(0)), &key()).unwrap();
+
+        for key in [
+            ConnectionKeys::from_secret([0x08; 32]).to_server,
+            ConnectionKeys::from_secret(SECRET).to_client,
+        ] {
+            assert!(matches!(
+                open(&octets, &key),
+                Err(ReceiveError::DecryptionFailed)
+            ));
+        }
+    }
+
+    #[test]
+    fn on_plaintext() {
+        assert!(matches!(
+            open(&ping(Some(0)), &key()),
+            Err(ReceiveError::DecryptionFailed)
+        ));
+        assert!(encrypt(&ping(None), &key()).is_err());
+    }
+}
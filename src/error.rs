@@ -0,0 +1,151 @@
+/*----------------------------------------------------------------------------------------------------------
+ *  Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/conclave-room-net-rs
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *--------------------------------------------------------------------------------------------------------*/
+//! Errors reported by the net layer
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use conclave_room::ConnectionIndex;
+
+/// Reasons an incoming datagram could not be handled by [`crate::ReceiveDatagram`].
+#[derive(Debug)]
+pub enum ReceiveError {
+    /// The connection index is not part of the room.
+    UnknownConnection(ConnectionIndex),
+    /// The sender is not a connection of the room and the datagram did not ask to join it.
+    JoinRequired,
+    /// The session token is missing or does not belong to the connection of the sender.
+    InvalidSessionToken,
+    /// The room requires authenticated datagrams and the MAC is missing or does not match.
+    InvalidMac,
+    /// The room requires encrypted datagrams and the body is not encrypted or could not be decrypted.
+    DecryptionFailed,
+    /// A datagram with this sequence number has already been received, or it is too old to tell.
+    Replayed(u64),
+    /// The datagram carries a session token but no sequence number.
+    MissingSequence,
+    /// The sender has used up its [`crate::RateLimit`], the datagram was not decoded.
+    RateLimited,
+    /// A join accept that does not answer our pending join request, like a duplicate or a replay.
+    UnexpectedJoinAccept,
+    /// The room already has [`crate::NetRoomConfig::max_connections`], the join was not accepted.
+    RoomFull,
+    /// A join request from a connection that has already sent sequenced datagrams, so it must have
+    /// received the join accept.
+    AlreadyJoined,
+    /// The datagram does not start with [`crate::DATAGRAM_MAGIC`].
+    InvalidMagic(u16),
+    /// The datagram was written by a different [`crate::PROTOCOL_VERSION`].
+    VersionMismatch { expected: u8, received: u8 },
+    /// The checksum at the end of the datagram does not match its contents.
+    ChecksumMismatch { expected: u32, calculated: u32 },
+    /// The datagram has no checksum, although the `checksum` feature requires one.
+    MissingChecksum,
+    /// The datagram ended in the middle of a command.
+    Truncated,
+    /// The datagram starts with a command type id that is not known.
+    UnknownCommandTypeId(u8),
+    /// A field in a command had a value that could not be decoded.
+    MalformedField(String),
+    /// The underlying octet stream failed for another reason.
+    Io(io::Error),
+}
+
+impl fmt::Display for ReceiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownConnection(connection_id) => {
+                write!(f, "there is no connection {}", connection_id)
+            }
+            Self::JoinRequired => write!(f, "sender has not joined the room"),
+            Self::InvalidSessionToken => write!(f, "missing or invalid session token"),
+            Self::InvalidMac => write!(f, "missing or invalid message authentication code"),
+            Self::DecryptionFailed => write!(f, "datagram could not be decrypted"),
+            Self::Replayed(sequence) => {
+                write!(f, "sequence {} is stale or was already received", sequence)
+            }
+            Self::MissingSequence => write!(f, "missing sequence number"),
+            Self::RateLimited => write!(f, "sender exceeded the rate limit"),
+            Self::UnexpectedJoinAccept => write!(f, "join accept without a pending join request"),
+            Self::RoomFull => write!(f, "room is full"),
+            Self::AlreadyJoined => write!(f, "join request after the join was accepted"),
+            Self::InvalidMagic(magic) => write!(f, "invalid datagram magic {:#06x}", magic),
+            Self::VersionMismatch { expected, received } => write!(
+                f,
+                "protocol version mismatch, expected {} but received {}",
+                expected, received
+            ),
+            Self::ChecksumMismatch {
+                expected,
+                calculated,
+            } => write!(
+                f,
+                "checksum mismatch, expected {:#010x} but calculated {:#010x}",
+                expected, calculated
+            ),
+            Self::MissingChecksum => write!(f, "datagram has no checksum"),
+            Self::Truncated => write!(f, "datagram is truncated"),
+            Self::UnknownCommandTypeId(command_type_id) => {
+                write!(f, "unknown command type id {:#04x}", command_type_id)
+            }
+            Self::MalformedField(description) => write!(f, "malformed field: {}", description),
+            Self::Io(err) => write!(f, "octet stream error: {}", err),
+        }
+    }
+}
+
+impl Error for ReceiveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ReceiveError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => Self::Truncated,
+            io::ErrorKind::InvalidData => Self::MalformedField(err.to_string()),
+            _ => Self::Io(err),
+        }
+    }
+}
+
+/// Reasons an outgoing datagram could not be produced by [`crate::SendDatagram`].
+#[derive(Debug)]
+pub enum SendError {
+    /// The connection index is not part of the room.
+    UnknownConnection(ConnectionIndex),
+    /// A command could not be written to the octet stream.
+    Serialize(io::Error),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownConnection(connection_id) => {
+                write!(f, "there is no connection {}", connection_id)
+            }
+            Self::Serialize(err) => write!(f, "failed to serialize command: {}", err),
+        }
+    }
+}
+
+impl Error for SendError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Serialize(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SendError {
+    fn from(err: io::Error) -> Self {
+        Self::Serialize(err)
+    }
+}
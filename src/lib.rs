@@ -5,37 +5,175 @@
 //! The Conclave Net Layer
 //!
 //! Easier to handle incoming network commands and construct outgoing messages
+#[cfg(feature = "auth")]
+mod auth;
+mod client;
+pub mod clock;
+mod commands;
+mod connection;
+mod datagram;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod error;
+mod net_room;
+mod rate_limit;
+mod registry;
+mod replay;
+#[cfg(feature = "server")]
+pub mod server;
+mod session;
+#[cfg(feature = "tokio")]
+pub mod tokio_server;
+pub mod transport;
+
 use std::time::Instant;
 
+#[cfg(feature = "auth")]
+pub use crate::auth::RoomKey;
+pub use crate::client::{ClientPing, ClientRoomView};
+use crate::commands::{read_commands, ClientCommand};
+pub use crate::commands::{
+    JOIN_ACCEPT_COMMAND_TYPE_ID, JOIN_REQUEST_COMMAND_TYPE_ID, LEAVE_COMMAND_TYPE_ID,
+    PING_ECHO_COMMAND_TYPE_ID, ROOM_INFO_COMMAND_TYPE_ID,
+};
+pub use crate::connection::{ConnectionStats, NetworkConnection};
+use crate::datagram::{finish_datagram, read_unsealed_datagram, write_header, DatagramHeader};
+pub use crate::datagram::{
+    CHECKSUM_FLAG, DATAGRAM_MAGIC, ENCRYPTED_FLAG, MAC_FLAG, PROTOCOL_VERSION, SEQUENCE_FLAG,
+    SESSION_TOKEN_FLAG,
+};
+pub use crate::error::{ReceiveError, SendError};
+pub use crate::net_room::{
+    NetRoom, NetRoomConfig, DEFAULT_CONNECTION_TIMEOUT, DEFAULT_MAX_CONNECTIONS,
+};
+pub use crate::rate_limit::{RateLimit, TokenBucket, DEFAULT_RATE_LIMIT};
+pub use crate::registry::ConnectionRegistry;
+pub use crate::replay::{ReplayWindow, REPLAY_WINDOW_SIZE};
+pub use crate::session::{JoinNonce, SessionToken};
 use conclave_room::{ConnectionIndex, Room};
-use conclave_room_serialize::{RoomInfoCommand, ServerReceiveCommand};
-use flood_rs::{OutOctetStream, ReadOctetStream};
+use conclave_room_serialize::{ClientInfo, RoomInfoCommand};
+use flood_rs::{OutOctetStream, ReadOctetStream, WriteOctetStream};
 
-pub struct NetworkConnection {
-    pub id: ConnectionIndex,
-    pub room: Room,
+fn client_infos(room: &Room) -> Vec<ClientInfo> {
+    let mut client_infos: Vec<ClientInfo> = room
+        .connections
+        .iter()
+        .map(|(connection_index, connection)| ClientInfo {
+            connection_index: *connection_index,
+            knowledge: connection.knowledge,
+            has_connection_to_leader: connection.has_connection_host,
+        })
+        .collect();
+    client_infos.sort_by_key(|client_info| client_info.connection_index);
+    client_infos
+}
+
+fn room_info_command(room: &Room) -> RoomInfoCommand {
+    RoomInfoCommand {
+        term: room.term,
+        leader_index: room.leader_index,
+        client_infos: client_infos(room),
+    }
 }
 
 pub trait SendDatagram {
-    fn send(&self) -> Vec<u8>;
+    /// Room info that is the same for every connection.
+    fn send(&self) -> Result<Vec<u8>, SendError>;
+
+    /// Room info tailored for `connection_id`, followed by the connection index of the recipient.
+    /// The recipient finds its own observed state in the client infos using that index.
+    fn send_to(&self, connection_id: ConnectionIndex) -> Result<Vec<u8>, SendError>;
 }
 
 impl SendDatagram for Room {
-    fn send(&self) -> Vec<u8> {
-        let room_info_command = RoomInfoCommand {
-            term: self.term,
-            leader_index: self.leader_index,
-            client_infos: vec![],
-        };
-
+    fn send(&self) -> Result<Vec<u8>, SendError> {
         let mut stream = OutOctetStream::new();
 
-        room_info_command
-            .to_octets(&mut stream)
-            .expect("Failed to write command {room_info_command:?} to octet stream");
+        write_header(&mut stream, &DatagramHeader::default())?;
+        stream.write_u8(ROOM_INFO_COMMAND_TYPE_ID)?;
+        room_info_command(self).to_octets(&mut stream)?;
+
+        Ok(finish_datagram(stream)?)
+    }
+
+    fn send_to(&self, connection_id: ConnectionIndex) -> Result<Vec<u8>, SendError> {
+        room_info_datagram(self, connection_id, &DatagramHeader::default(), None)
+    }
+}
+
+/// Room info tailored for `connection_id` behind `header`, followed by the `server_time` that the
+/// recipient should echo if it is set.
+pub(crate) fn room_info_datagram(
+    room: &Room,
+    connection_id: ConnectionIndex,
+    header: &DatagramHeader,
+    server_time: Option<u32>,
+) -> Result<Vec<u8>, SendError> {
+    if !room.connections.contains_key(&connection_id) {
+        return Err(SendError::UnknownConnection(connection_id));
+    }
+
+    let mut stream = OutOctetStream::new();
 
-        stream.data
+    write_header(&mut stream, header)?;
+    stream.write_u8(ROOM_INFO_COMMAND_TYPE_ID)?;
+    room_info_command(room).to_octets(&mut stream)?;
+    stream.write_u8(connection_id)?;
+    if let Some(server_time) = server_time {
+        stream.write_u32(server_time)?;
     }
+
+    Ok(finish_datagram(stream)?)
+}
+
+pub trait BroadcastDatagram: SendDatagram {
+    /// One [`SendDatagram::send_to`] datagram for every connection in the room, ordered by connection index.
+    fn broadcast(&self) -> Result<Vec<(ConnectionIndex, Vec<u8>)>, SendError>;
+}
+
+impl BroadcastDatagram for Room {
+    fn broadcast(&self) -> Result<Vec<(ConnectionIndex, Vec<u8>)>, SendError> {
+        let mut connection_ids: Vec<ConnectionIndex> = self.connections.keys().copied().collect();
+        connection_ids.sort();
+
+        connection_ids
+            .into_iter()
+            .map(|connection_id| Ok((connection_id, self.send_to(connection_id)?)))
+            .collect()
+    }
+}
+
+/// Applies commands from `connection_id` to the room. A leave destroys the connection, so any
+/// commands after it are ignored. Join requests and ping echoes are handled by [`NetRoom`], the room
+/// ignores them.
+pub(crate) fn apply_commands(
+    room: &mut Room,
+    connection_id: ConnectionIndex,
+    now: Instant,
+    commands: Vec<ClientCommand>,
+) -> Result<(), ReceiveError> {
+    if !room.connections.contains_key(&connection_id) {
+        return Err(ReceiveError::UnknownConnection(connection_id));
+    }
+    for command in commands {
+        match command {
+            ClientCommand::Ping(ping_command) => {
+                room.on_ping(
+                    connection_id,
+                    ping_command.term,
+                    ping_command.has_connection_to_leader,
+                    ping_command.knowledge,
+                    now,
+                );
+            }
+            ClientCommand::JoinRequest { .. } | ClientCommand::PingEcho { .. } => {}
+            ClientCommand::Leave => {
+                room.destroy_connection(connection_id);
+                break;
+            }
+        }
+    }
+    Ok(())
 }
 
 pub trait ReceiveDatagram {
@@ -44,32 +182,24 @@ pub trait ReceiveDatagram {
         connection_id: ConnectionIndex,
         now: Instant,
         buffer: &mut impl ReadOctetStream,
-    ) -> Result<(), String>;
+    ) -> Result<(), ReceiveError>;
 }
 
+/// Applies the commands of an unsealed datagram. A datagram with a MAC, an encrypted body or a
+/// session token is rejected, those are only accepted by a [`NetRoom`].
 impl ReceiveDatagram for Room {
     fn receive(
         &mut self,
         connection_id: ConnectionIndex,
         now: Instant,
         reader: &mut impl ReadOctetStream,
-    ) -> Result<(), String> {
+    ) -> Result<(), ReceiveError> {
         if !self.connections.contains_key(&connection_id) {
-            return Err(format!("there is no connection {}", connection_id));
-        }
-        let command = ServerReceiveCommand::from_cursor(reader).unwrap();
-        match command {
-            ServerReceiveCommand::PingCommandType(ping_command) => {
-                self.on_ping(
-                    connection_id,
-                    ping_command.term,
-                    ping_command.has_connection_to_leader,
-                    ping_command.knowledge,
-                    now,
-                );
-            }
+            return Err(ReceiveError::UnknownConnection(connection_id));
         }
-        Ok(())
+        let mut body = read_unsealed_datagram(reader)?;
+        let commands = read_commands(&mut body)?;
+        apply_commands(self, connection_id, now, commands)
     }
 }
 
@@ -77,46 +207,378 @@ impl ReceiveDatagram for Room {
 mod tests {
     use std::time::Instant;
 
-    use conclave_room::Room;
-    use conclave_room_serialize::PING_COMMAND_TYPE_ID;
-    use flood_rs::InOctetStream;
+    use conclave_room::{ConnectionIndex, Room};
+    use conclave_room_serialize::{RoomInfoCommand, PING_COMMAND_TYPE_ID};
+    use flood_rs::{InOctetStream, ReadOctetStream};
+
+    use crate::datagram::{read_datagram, MAC_SIZE};
+    use crate::{
+        BroadcastDatagram, ClientPing, ReceiveDatagram, ReceiveError, SendDatagram, SendError,
+        CHECKSUM_FLAG, DATAGRAM_MAGIC, ENCRYPTED_FLAG, LEAVE_COMMAND_TYPE_ID, MAC_FLAG,
+        PROTOCOL_VERSION, ROOM_INFO_COMMAND_TYPE_ID,
+    };
+
+    const PING_OCTETS: [u8; 12] = [
+        PING_COMMAND_TYPE_ID,
+        0x00, // Term
+        0x20,
+        0xF5, // Knowledge
+        0xE6,
+        0x0E,
+        0x32,
+        0xE9,
+        0xE4,
+        0x7F,
+        0x08,
+        0x01, // Has connection to leader
+    ];
+
+    fn datagram(commands: &[u8]) -> Vec<u8> {
+        flagged_datagram(0x00, commands)
+    }
+
+    /// Adds the checksum that is required with the `checksum` feature.
+    fn flagged_datagram(flags: u8, commands: &[u8]) -> Vec<u8> {
+        let checksum = cfg!(feature = "checksum");
+        let mut octets = DATAGRAM_MAGIC.to_be_bytes().to_vec();
+        octets.push(PROTOCOL_VERSION);
+        octets.push(if checksum {
+            flags | CHECKSUM_FLAG
+        } else {
+            flags
+        });
+        octets.extend_from_slice(commands);
+        if checksum {
+            let checksum = crc32fast::hash(&octets);
+            octets.extend_from_slice(&checksum.to_be_bytes());
+        }
+        octets
+    }
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn check_send() {
+        let room = Room::new();
+        let octets = room.send().unwrap();
 
-    use crate::{ReceiveDatagram, SendDatagram};
+        assert_eq!(
+            vec![
+                0x43,
+                0x52,
+                PROTOCOL_VERSION,
+                0x00,
+                ROOM_INFO_COMMAND_TYPE_ID,
+                0x00,
+                0x00,
+                0x00,
+                0xff
+            ],
+            octets
+        );
+    }
 
     #[test]
+    #[cfg(feature = "checksum")]
     fn check_send() {
+        use crate::CHECKSUM_FLAG;
+
         let room = Room::new();
-        let octets = room.send();
+        let octets = room.send().unwrap();
+
+        let (datagram, checksum) = octets.split_at(octets.len() - 4);
+        assert_eq!(
+            datagram,
+            [
+                0x43,
+                0x52,
+                PROTOCOL_VERSION,
+                CHECKSUM_FLAG,
+                ROOM_INFO_COMMAND_TYPE_ID,
+                0x00,
+                0x00,
+                0x00,
+                0xff
+            ]
+        );
+        assert_eq!(checksum, crc32fast::hash(datagram).to_be_bytes());
+    }
+
+    #[test]
+    fn check_send_client_infos() {
+        let mut room = Room::new();
+        let now = Instant::now();
+        let first_connection_id = room.create_connection(now);
+        let second_connection_id = room.create_connection(now);
+        room.on_ping(second_connection_id, room.term, true, 42, now);
+
+        let octets = room.send().unwrap();
+        let (_, mut receive_cursor) = read_datagram(&mut InOctetStream::new(octets)).unwrap();
+        assert_eq!(receive_cursor.read_u8().unwrap(), ROOM_INFO_COMMAND_TYPE_ID);
+        let room_info = RoomInfoCommand::from_cursor(&mut receive_cursor).unwrap();
+
+        let connection_indices: Vec<ConnectionIndex> = room_info
+            .client_infos
+            .iter()
+            .map(|client_info| client_info.connection_index)
+            .collect();
+        assert_eq!(
+            connection_indices,
+            vec![first_connection_id, second_connection_id]
+        );
+        assert_eq!(room_info.client_infos[1].knowledge, 42);
+        assert!(room_info.client_infos[1].has_connection_to_leader);
+    }
+
+    #[test]
+    fn check_send_to() {
+        let mut room = Room::new();
+        let now = Instant::now();
+        room.create_connection(now);
+        let second_connection_id = room.create_connection(now);
+
+        let octets = room.send_to(second_connection_id).unwrap();
+        let (_, mut receive_cursor) = read_datagram(&mut InOctetStream::new(octets)).unwrap();
+        assert_eq!(receive_cursor.read_u8().unwrap(), ROOM_INFO_COMMAND_TYPE_ID);
+        let room_info = RoomInfoCommand::from_cursor(&mut receive_cursor).unwrap();
+        assert_eq!(room_info.client_infos.len(), 2);
+        assert_eq!(receive_cursor.read_u8().unwrap(), second_connection_id);
+        assert!(receive_cursor.has_reached_end());
+    }
+
+    #[test]
+    fn check_send_to_unknown_connection() {
+        let room = Room::new();
+        assert!(matches!(
+            room.send_to(42),
+            Err(SendError::UnknownConnection(42))
+        ));
+    }
 
-        assert_eq!(vec![0x00, 0x00, 0x00, 0xff], octets);
+    #[test]
+    fn check_broadcast() {
+        let mut room = Room::new();
+        let now = Instant::now();
+        let first_connection_id = room.create_connection(now);
+        let second_connection_id = room.create_connection(now);
+
+        let datagrams = room.broadcast().unwrap();
+        assert_eq!(
+            datagrams,
+            vec![
+                (
+                    first_connection_id,
+                    room.send_to(first_connection_id).unwrap()
+                ),
+                (
+                    second_connection_id,
+                    room.send_to(second_connection_id).unwrap()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn check_ping_datagram() {
+        let ping = ClientPing {
+            term: 0x0020,
+            knowledge: 17718865395771014920,
+            has_connection_to_leader: true,
+        };
+
+        assert_eq!(ping.to_datagram().unwrap(), datagram(&PING_OCTETS));
     }
 
     #[test]
     fn on_ping() {
         const EXPECTED_KNOWLEDGE_VALUE: u64 = 17718865395771014920;
-        let octets = [
-            PING_COMMAND_TYPE_ID,
-            0x00, // Term
-            0x20,
-            0xF5, // Knowledge
-            0xE6,
-            0x0E,
-            0x32,
-            0xE9,
-            0xE4,
-            0x7F,
-            0x08,
-            0x01, // Has connection to leader
-        ];
-        let mut receive_cursor = InOctetStream::new(octets.into());
+        let ping = ClientPing {
+            term: 0x0020,
+            knowledge: EXPECTED_KNOWLEDGE_VALUE,
+            has_connection_to_leader: true,
+        };
+        let mut receive_cursor = InOctetStream::new(ping.to_datagram().unwrap());
 
         let mut room = Room::new();
         let now = Instant::now();
         let first_connection_id = room.create_connection(now);
         let receive_result = room.receive(first_connection_id, now, &mut receive_cursor);
-        assert_eq!(receive_result, Ok(()));
+        assert!(receive_result.is_ok());
 
         let connection_after_receive = room.connections.get(&first_connection_id).unwrap();
         assert_eq!(connection_after_receive.knowledge, EXPECTED_KNOWLEDGE_VALUE);
     }
+
+    #[test]
+    fn on_multiple_pings() {
+        let mut commands = PING_OCTETS.to_vec();
+        commands.extend_from_slice(&PING_OCTETS);
+        commands[PING_OCTETS.len() + 10] = 0x09; // Last knowledge octet of the second ping
+        let mut receive_cursor = InOctetStream::new(datagram(&commands));
+
+        let mut room = Room::new();
+        let now = Instant::now();
+        let first_connection_id = room.create_connection(now);
+        let receive_result = room.receive(first_connection_id, now, &mut receive_cursor);
+        assert!(receive_result.is_ok());
+
+        let connection_after_receive = room.connections.get(&first_connection_id).unwrap();
+        assert_eq!(connection_after_receive.knowledge, 17718865395771014921);
+    }
+
+    #[test]
+    fn on_ping_with_trailing_octets() {
+        let mut commands = PING_OCTETS.to_vec();
+        commands.extend_from_slice(&PING_OCTETS[..3]);
+        let mut receive_cursor = InOctetStream::new(datagram(&commands));
+
+        let mut room = Room::new();
+        let now = Instant::now();
+        let first_connection_id = room.create_connection(now);
+        let receive_result = room.receive(first_connection_id, now, &mut receive_cursor);
+        assert!(matches!(receive_result, Err(ReceiveError::Truncated)));
+
+        let connection_after_receive = room.connections.get(&first_connection_id).unwrap();
+        assert_eq!(connection_after_receive.knowledge, 0);
+    }
+
+    #[test]
+    fn on_leave() {
+        let mut commands = vec![LEAVE_COMMAND_TYPE_ID];
+        commands.extend_from_slice(&PING_OCTETS);
+        let mut receive_cursor = InOctetStream::new(datagram(&commands));
+
+        let mut room = Room::new();
+        let now = Instant::now();
+        let first_connection_id = room.create_connection(now);
+        let second_connection_id = room.create_connection(now);
+        room.receive(first_connection_id, now, &mut receive_cursor)
+            .unwrap();
+
+        assert!(!room.connections.contains_key(&first_connection_id));
+        assert!(room.connections.contains_key(&second_connection_id));
+    }
+
+    #[test]
+    fn on_ping_unknown_connection() {
+        let mut receive_cursor = InOctetStream::new(datagram(&PING_OCTETS));
+
+        let mut room = Room::new();
+        let receive_result = room.receive(42, Instant::now(), &mut receive_cursor);
+        assert!(matches!(
+            receive_result,
+            Err(ReceiveError::UnknownConnection(42))
+        ));
+    }
+
+    fn receive_octets(octets: &[u8]) -> Result<(), ReceiveError> {
+        let mut room = Room::new();
+        let now = Instant::now();
+        let connection_id = room.create_connection(now);
+        let mut receive_cursor = InOctetStream::new(octets.to_vec());
+        room.receive(connection_id, now, &mut receive_cursor)
+    }
+
+    #[test]
+    fn on_empty_datagram() {
+        assert!(matches!(receive_octets(&[]), Err(ReceiveError::Truncated)));
+    }
+
+    #[test]
+    fn on_header_only() {
+        assert!(matches!(
+            receive_octets(&datagram(&[])),
+            Err(ReceiveError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn on_invalid_magic() {
+        let mut octets = datagram(&PING_OCTETS);
+        octets[0] = 0x00;
+        assert!(matches!(
+            receive_octets(&octets),
+            Err(ReceiveError::InvalidMagic(0x0052))
+        ));
+    }
+
+    #[test]
+    fn on_version_mismatch() {
+        let mut octets = datagram(&PING_OCTETS);
+        octets[2] = PROTOCOL_VERSION + 1;
+        assert!(matches!(
+            receive_octets(&octets),
+            Err(ReceiveError::VersionMismatch { expected, received })
+                if expected == PROTOCOL_VERSION && received == PROTOCOL_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn on_truncated_ping() {
+        let octets = datagram(&PING_OCTETS);
+        for length in 1..4 {
+            let receive_result = receive_octets(&octets[..length]);
+            assert!(
+                matches!(receive_result, Err(ReceiveError::Truncated)),
+                "length {length} gave {receive_result:?}"
+            );
+        }
+        for length in 0..PING_OCTETS.len() {
+            let receive_result = receive_octets(&datagram(&PING_OCTETS[..length]));
+            assert!(
+                matches!(receive_result, Err(ReceiveError::Truncated)),
+                "command length {length} gave {receive_result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn on_sealed_datagrams() {
+        let mut commands = PING_OCTETS.to_vec();
+        commands.extend_from_slice(&[0x00; MAC_SIZE]);
+        assert!(matches!(
+            receive_octets(&flagged_datagram(MAC_FLAG, &commands)),
+            Err(ReceiveError::InvalidMac)
+        ));
+        assert!(matches!(
+            receive_octets(&flagged_datagram(ENCRYPTED_FLAG, &commands)),
+            Err(ReceiveError::DecryptionFailed)
+        ));
+
+        let ping = ClientPing {
+            term: 0,
+            knowledge: 42,
+            has_connection_to_leader: false,
+        };
+        assert!(matches!(
+            receive_octets(&ping.to_session_datagram(Some(1)).unwrap()),
+            Err(ReceiveError::InvalidSessionToken)
+        ));
+    }
+
+    #[test]
+    fn on_unknown_command_type_id() {
+        let mut commands = PING_OCTETS;
+        commands[0] = PING_COMMAND_TYPE_ID.wrapping_add(1);
+        assert!(matches!(
+            receive_octets(&datagram(&commands)),
+            Err(ReceiveError::UnknownCommandTypeId(id)) if id == commands[0]
+        ));
+    }
+
+    #[test]
+    fn receive_never_panics() {
+        for first in 0..=u8::MAX {
+            for fill in [0x00, 0x01, 0x7f, 0xff] {
+                for length in 0..=PING_OCTETS.len() + 1 {
+                    let mut commands = vec![fill; length];
+                    if let Some(command_type_id) = commands.first_mut() {
+                        *command_type_id = first;
+                    }
+                    let _ = receive_octets(&commands);
+                    let _ = receive_octets(&datagram(&commands));
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,936 @@
+/*----------------------------------------------------------------------------------------------------------
+ *  Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/conclave-room-net-rs
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *--------------------------------------------------------------------------------------------------------*/
+//! A room shared by all of its network connections
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use conclave_room::{ConnectionIndex, Room};
+use flood_rs::InOctetStream;
+
+#[cfg(feature = "auth")]
+use crate::auth::{self, RoomKey};
+use crate::commands::{join_accept_datagram, read_commands, ClientCommand};
+use crate::datagram::{read_datagram, DatagramHeader};
+use crate::registry::ConnectionRegistry;
+use crate::{
+    apply_commands, room_info_datagram, ConnectionStats, JoinNonce, NetworkConnection, RateLimit,
+    ReceiveError, SendError, TokenBucket, DEFAULT_RATE_LIMIT,
+};
+
+/// Connections that have not sent an accepted datagram for this long are dropped by [`NetRoom::tick`].
+pub const DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Used by [`NetRoomConfig::default`].
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct NetRoomConfig {
+    pub connection_timeout: Duration,
+    /// Join requests from new addresses are rejected once the room has this many connections.
+    /// Should not be above 256, the number of connections a [`ConnectionIndex`] can tell apart.
+    pub max_connections: usize,
+    /// Datagrams beyond this rate are dropped before they are decoded. Counted per connection, or
+    /// per address for senders that have not joined. `None` disables the limit.
+    pub rate_limit: Option<RateLimit>,
+    /// When set, every datagram in both directions is authenticated with a key derived from it.
+    /// With the `encryption` feature, datagrams of joined connections are also encrypted.
+    #[cfg(feature = "auth")]
+    pub room_key: Option<RoomKey>,
+}
+
+impl Default for NetRoomConfig {
+    fn default() -> Self {
+        Self {
+            connection_timeout: DEFAULT_CONNECTION_TIMEOUT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            rate_limit: Some(DEFAULT_RATE_LIMIT),
+            #[cfg(feature = "auth")]
+            room_key: None,
+        }
+    }
+}
+
+impl NetRoomConfig {
+    /// Seals a datagram for `connection` if the room has a key, see [`auth::seal`].
+    #[cfg_attr(not(feature = "auth"), allow(unused_variables))]
+    fn seal<A>(&self, octets: Vec<u8>, connection: &NetworkConnection<A>) -> io::Result<Vec<u8>> {
+        #[cfg(feature = "auth")]
+        if let Some(room_key) = &self.room_key {
+            let connection_key = connection.keys.as_ref().map(|keys| &keys.to_client);
+            return auth::seal(&octets, room_key, connection_key);
+        }
+        Ok(octets)
+    }
+
+    /// The sealed answer to the join request with `nonce`. Carries the masked secret of the
+    /// connection if the room has a key, see [`auth::mask_secret`].
+    fn join_accept<A>(
+        &self,
+        connection: &NetworkConnection<A>,
+        nonce: JoinNonce,
+    ) -> io::Result<Vec<u8>> {
+        #[cfg(feature = "auth")]
+        if let (Some(room_key), Some(keys)) = (&self.room_key, &connection.keys) {
+            let masked_secret =
+                auth::mask_secret(&keys.secret, room_key, nonce, connection.session_token);
+            let octets = join_accept_datagram(
+                connection.id,
+                connection.session_token,
+                nonce,
+                Some(&masked_secret),
+            )?;
+            return self.seal(octets, connection);
+        }
+        join_accept_datagram(connection.id, connection.session_token, nonce, None)
+    }
+
+    /// Opens a datagram from `connection`, the connection at the address of the sender if there
+    /// is one, and returns the commands. See [`auth::open`].
+    #[cfg_attr(not(feature = "auth"), allow(unused_variables))]
+    fn open<A>(
+        &self,
+        octets: &[u8],
+        body: InOctetStream,
+        connection: Option<&NetworkConnection<A>>,
+    ) -> Result<InOctetStream, ReceiveError> {
+        #[cfg(feature = "auth")]
+        if let Some(room_key) = &self.room_key {
+            let connection_key = connection
+                .and_then(|connection| connection.keys.as_ref())
+                .map(|keys| &keys.to_server);
+            return auth::open(octets, body, room_key, connection_key);
+        }
+        Ok(body)
+    }
+}
+
+/// Owns the [`Room`] and the [`crate::NetworkConnection`] of every peer, independent of how
+/// datagrams are transported.
+#[derive(Debug)]
+pub struct NetRoom<A> {
+    pub room: Room,
+    pub connections: ConnectionRegistry<A>,
+    pub config: NetRoomConfig,
+    /// Datagrams dropped by the rate limit, both from connections and from addresses that have
+    /// not joined.
+    pub rate_limited: u64,
+    address_rate_limiters: HashMap<A, TokenBucket>,
+    /// Removed from the registry, but not yet returned by [`NetRoom::tick`].
+    pending_dropped: Vec<ConnectionIndex>,
+}
+
+impl<A: Copy + Eq + Hash> Default for NetRoom<A> {
+    fn default() -> Self {
+        Self::with_config(NetRoomConfig::default())
+    }
+}
+
+impl<A: Copy + Eq + Hash> NetRoom<A> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: NetRoomConfig) -> Self {
+        Self {
+            room: Room::new(),
+            connections: ConnectionRegistry::new(),
+            config,
+            rate_limited: 0,
+            address_rate_limiters: HashMap::new(),
+            pending_dropped: Vec::new(),
+        }
+    }
+
+    /// Feeds a datagram from `address` into the room. Only a datagram with a join request can
+    /// create a connection, as long as the room is not full, and join requests are answered with a
+    /// queued join accept until the connection sends its first sequenced datagram. Resent join
+    /// requests do not count as received in the connection stats, so they can not keep it alive.
+    /// All other datagrams must carry the session token from that join accept.
+    /// A leave destroys the connection in the room, it is removed from the registry by the next
+    /// [`NetRoom::tick`]. If the room has a key, the MAC is verified or the commands are decrypted
+    /// before anything else. Datagrams with a session token must also carry a sequence number, and
+    /// stale or duplicate sequence numbers are rejected and counted in the connection stats, as are
+    /// datagrams that could not be decoded. Ping echoes update the round trip time.
+    pub fn receive_from(
+        &mut self,
+        address: A,
+        now: Instant,
+        octets: &[u8],
+    ) -> Result<ConnectionIndex, ReceiveError> {
+        if let Some(rate_limit) = self.config.rate_limit {
+            self.take_token(address, &rate_limit, now)?;
+        }
+
+        let (header, commands) = match self.decode(address, octets) {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                let connection_id = self.connections.connection_id(&address);
+                if let Some(connection) =
+                    connection_id.and_then(|connection_id| self.connections.get_mut(connection_id))
+                {
+                    connection.stats.decode_errors += 1;
+                }
+                return Err(err);
+            }
+        };
+        let join_nonce = commands.iter().rev().find_map(|command| match command {
+            ClientCommand::JoinRequest { nonce } => Some(*nonce),
+            _ => None,
+        });
+        let only_joins = commands
+            .iter()
+            .all(|command| matches!(command, ClientCommand::JoinRequest { .. }));
+
+        self.remove_dropped();
+        let (connection_id, created) = match self.connections.connection_id(&address) {
+            Some(connection_id) => (connection_id, false),
+            None if only_joins && header.session_token.is_none() => {
+                if self.connections.len() >= self.config.max_connections {
+                    return Err(ReceiveError::RoomFull);
+                }
+                self.connections
+                    .get_or_create(address, &mut self.room, now)?
+            }
+            None => return Err(ReceiveError::JoinRequired),
+        };
+
+        let session_token = self
+            .connections
+            .get(connection_id)
+            .map(|connection| connection.session_token);
+        match header.session_token {
+            Some(received) if Some(received) == session_token => {}
+            // A join request is resent until the join accept arrives
+            None if only_joins => {}
+            _ => return Err(ReceiveError::InvalidSessionToken),
+        }
+
+        if let Some(connection) = self.connections.get_mut(connection_id) {
+            let joined = !connection.replay_window.is_empty();
+            if joined && header.session_token.is_none() {
+                return Err(ReceiveError::AlreadyJoined);
+            }
+            if header.session_token.is_some() {
+                let Some(sequence) = header.sequence else {
+                    return Err(ReceiveError::MissingSequence);
+                };
+                if !connection.replay_window.accept(sequence) {
+                    connection.stats.packets_replayed += 1;
+                    return Err(ReceiveError::Replayed(sequence));
+                }
+            }
+
+            for command in &commands {
+                if let ClientCommand::PingEcho {
+                    server_time,
+                    held_for_ms,
+                } = *command
+                {
+                    let held_for = Duration::from_millis(u64::from(held_for_ms));
+                    connection.on_ping_echo(server_time, held_for, now);
+                }
+            }
+
+            if let Some(nonce) = join_nonce.filter(|_| !joined) {
+                let join_accept = self.config.join_accept(connection, nonce)?;
+                connection.queue(join_accept);
+            }
+        }
+
+        apply_commands(&mut self.room, connection_id, now, commands)?;
+
+        if created || header.session_token.is_some() {
+            if let Some(connection) = self.connections.get_mut(connection_id) {
+                connection.on_received(octets.len(), now);
+            }
+        }
+
+        Ok(connection_id)
+    }
+
+    fn decode(
+        &self,
+        address: A,
+        octets: &[u8],
+    ) -> Result<(DatagramHeader, Vec<ClientCommand>), ReceiveError> {
+        let (header, body) = read_datagram(&mut InOctetStream::new(octets.to_vec()))?;
+        let connection = self
+            .connections
+            .connection_id(&address)
+            .and_then(|connection_id| self.connections.get(connection_id));
+        let mut body = self.config.open(octets, body, connection)?;
+        let commands = read_commands(&mut body)?;
+        Ok((header, commands))
+    }
+
+    /// Takes a token from the bucket of the connection at `address`, or from the bucket of the
+    /// address itself if it has not joined.
+    fn take_token(
+        &mut self,
+        address: A,
+        rate_limit: &RateLimit,
+        now: Instant,
+    ) -> Result<(), ReceiveError> {
+        let connection = self
+            .connections
+            .connection_id(&address)
+            .and_then(|connection_id| self.connections.get_mut(connection_id));
+        let is_allowed = match connection {
+            Some(connection) => {
+                let is_allowed = connection.rate_limiter.try_take(rate_limit, now);
+                if !is_allowed {
+                    connection.stats.packets_rate_limited += 1;
+                }
+                is_allowed
+            }
+            None => self
+                .address_rate_limiters
+                .entry(address)
+                .or_default()
+                .try_take(rate_limit, now),
+        };
+
+        if !is_allowed {
+            self.rate_limited += 1;
+            return Err(ReceiveError::RateLimited);
+        }
+        Ok(())
+    }
+
+    /// Removes connections that have been silent for longer than the configured timeout, from both
+    /// the registry and the room. Returns every connection that was dropped, including the ones the
+    /// room dropped by itself since the last tick. Also forgets the rate limit of addresses that have
+    /// been quiet long enough.
+    pub fn tick(&mut self, now: Instant) -> Vec<ConnectionIndex> {
+        let timeout = self.config.connection_timeout;
+        let timed_out: Vec<ConnectionIndex> = self
+            .connections
+            .iter()
+            .filter(|connection| connection.silent_for(now) > timeout)
+            .map(|connection| connection.id)
+            .collect();
+
+        for connection_id in &timed_out {
+            self.room.destroy_connection(*connection_id);
+        }
+
+        self.remove_dropped();
+        let mut dropped = mem::take(&mut self.pending_dropped);
+        dropped.sort();
+
+        // Buckets that have refilled are the same as new ones
+        match self.config.rate_limit {
+            Some(rate_limit) => self
+                .address_rate_limiters
+                .retain(|_, bucket| !bucket.is_full(&rate_limit, now)),
+            None => self.address_rate_limiters.clear(),
+        }
+
+        dropped
+    }
+
+    /// Removes the connections that the room no longer has from the registry, remembering them
+    /// for the next [`NetRoom::tick`].
+    fn remove_dropped(&mut self) {
+        let dropped = self.connections.remove_dropped(&self.room);
+        self.pending_dropped
+            .extend(dropped.into_iter().map(|connection| connection.id));
+    }
+
+    /// Queues the personalized room info on every connection, numbered and stamped with the server
+    /// time of the connection at `now`.
+    pub fn queue_broadcast(&mut self, now: Instant) -> Result<(), SendError> {
+        self.remove_dropped();
+
+        for connection in self.connections.iter_mut() {
+            let header = DatagramHeader {
+                session_token: None,
+                sequence: Some(connection.next_sequence()),
+            };
+            let server_time = connection.server_time(now);
+            let octets = room_info_datagram(&self.room, connection.id, &header, Some(server_time))?;
+            let octets = self.config.seal(octets, connection)?;
+            connection.queue(octets);
+        }
+
+        Ok(())
+    }
+
+    /// Traffic statistics of `connection_id`, if it is a connection of the room.
+    pub fn stats(&self, connection_id: ConnectionIndex) -> Option<ConnectionStats> {
+        self.connections
+            .get(connection_id)
+            .map(|connection| connection.stats)
+    }
+
+    /// Traffic statistics of every connection, ordered by connection index.
+    pub fn all_stats(&self) -> Vec<(ConnectionIndex, ConnectionStats)> {
+        let mut all_stats: Vec<(ConnectionIndex, ConnectionStats)> = self
+            .connections
+            .iter()
+            .map(|connection| (connection.id, connection.stats))
+            .collect();
+        all_stats.sort_by_key(|(connection_id, _)| *connection_id);
+        all_stats
+    }
+
+    /// Should be called when a datagram taken by [`NetRoom::drain_outgoing`] could not be sent.
+    pub fn on_send_failed(&mut self, address: &A) {
+        if let Some(connection) = self
+            .connections
+            .connection_id(address)
+            .and_then(|connection_id| self.connections.get_mut(connection_id))
+        {
+            connection.stats.send_errors += 1;
+        }
+    }
+
+    /// Takes every queued datagram together with the address it should be sent to.
+    pub fn drain_outgoing(&mut self) -> Vec<(A, Vec<u8>)> {
+        let mut outgoing = Vec::new();
+        for connection in self.connections.iter_mut() {
+            while let Some(octets) = connection.pop_outgoing() {
+                outgoing.push((connection.address, octets));
+            }
+        }
+        outgoing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use flood_rs::InOctetStream;
+
+    use crate::clock::{Clock, ManualClock};
+    use crate::net_room::NetRoom;
+    use crate::{ClientPing, ClientRoomView, RateLimit, ReceiveDatagram, ReceiveError};
+
+    /// Joins from `address` and returns the client view after it received the join accept.
+    fn join(
+        net_room: &mut NetRoom<&'static str>,
+        address: &'static str,
+        now: Instant,
+    ) -> ClientRoomView {
+        let mut view = ClientRoomView::new();
+        net_room
+            .receive_from(address, now, &view.join_request().unwrap())
+            .unwrap();
+        for (_, octets) in net_room
+            .drain_outgoing()
+            .into_iter()
+            .filter(|(receiver, _)| *receiver == address)
+        {
+            view.receive(0, now, &mut InOctetStream::new(octets))
+                .unwrap();
+        }
+        view
+    }
+
+    #[test]
+    fn join_and_leave() {
+        let mut net_room = NetRoom::new();
+        let now = Instant::now();
+        let mut view = join(&mut net_room, "first", now);
+        let connection_id = view.own_index.unwrap();
+        let session_token = view.session_token.unwrap();
+        assert_eq!(
+            net_room
+                .connections
+                .get(connection_id)
+                .unwrap()
+                .session_token,
+            session_token
+        );
+
+        let resent_view = join(&mut net_room, "first", now);
+        assert_eq!(resent_view.own_index, Some(connection_id));
+        assert_eq!(resent_view.session_token, Some(session_token));
+
+        net_room
+            .receive_from("first", now, &view.leave().unwrap())
+            .unwrap();
+        assert!(net_room.room.connections.is_empty());
+        assert_eq!(net_room.tick(now), vec![connection_id]);
+        assert!(net_room.connections.is_empty());
+    }
+
+    #[test]
+    fn report_leave_handled_before_other_datagrams() {
+        let mut net_room = NetRoom::new();
+        let now = Instant::now();
+        let mut first = join(&mut net_room, "first", now);
+        let mut second = join(&mut net_room, "second", now);
+
+        net_room
+            .receive_from("first", now, &first.leave().unwrap())
+            .unwrap();
+        net_room
+            .receive_from("second", now, &second.ping(1, false, now).unwrap())
+            .unwrap();
+        net_room.queue_broadcast(now).unwrap();
+        assert_eq!(net_room.tick(now), vec![first.own_index.unwrap()]);
+        assert!(net_room.tick(now).is_empty());
+    }
+
+    #[test]
+    fn stop_accepting_joins_after_first_ping() {
+        let mut net_room = NetRoom::new();
+        let now = Instant::now();
+        let mut view = join(&mut net_room, "first", now);
+        let connection_id = view.own_index.unwrap();
+
+        let later = now + Duration::from_secs(1);
+        join(&mut net_room, "first", later);
+        let stats = &net_room.connections.get(connection_id).unwrap().stats;
+        assert_eq!(stats.packets_received, 1);
+        assert_eq!(stats.last_received_at, Some(now));
+
+        net_room
+            .receive_from("first", later, &view.ping(1, false, later).unwrap())
+            .unwrap();
+        assert!(matches!(
+            net_room.receive_from("first", later, &view.join_request().unwrap()),
+            Err(ReceiveError::AlreadyJoined)
+        ));
+        assert!(net_room.drain_outgoing().is_empty());
+        let stats = &net_room.connections.get(connection_id).unwrap().stats;
+        assert_eq!(stats.packets_received, 2);
+    }
+
+    #[test]
+    fn reject_ping_before_join() {
+        let mut net_room = NetRoom::new();
+        let ping = ClientRoomView::new()
+            .ping(0, false, Instant::now())
+            .unwrap();
+
+        assert!(matches!(
+            net_room.receive_from("first", Instant::now(), &ping),
+            Err(ReceiveError::JoinRequired)
+        ));
+        assert!(net_room.room.connections.is_empty());
+        assert!(net_room.connections.is_empty());
+    }
+
+    #[test]
+    fn reject_invalid_session_token() {
+        let mut net_room = NetRoom::new();
+        let now = Instant::now();
+        let view = join(&mut net_room, "first", now);
+        let ping = ClientPing {
+            term: 0,
+            knowledge: 42,
+            has_connection_to_leader: false,
+        };
+
+        for session_token in [None, Some(view.session_token.unwrap().wrapping_add(1))] {
+            assert!(matches!(
+                net_room.receive_from(
+                    "first",
+                    now,
+                    &ping.to_session_datagram(session_token).unwrap()
+                ),
+                Err(ReceiveError::InvalidSessionToken)
+            ));
+        }
+        let connection_id = view.own_index.unwrap();
+        let connection = net_room.room.connections.get(&connection_id).unwrap();
+        assert_eq!(connection.knowledge, 0);
+    }
+
+    #[test]
+    fn reject_replayed_ping() {
+        let mut net_room = NetRoom::new();
+        let now = Instant::now();
+        let mut view = join(&mut net_room, "first", now);
+
+        let first_ping = view.ping(1, false, now).unwrap();
+        let second_ping = view.ping(2, false, now).unwrap();
+        net_room.receive_from("first", now, &second_ping).unwrap();
+        net_room.receive_from("first", now, &first_ping).unwrap();
+        assert!(matches!(
+            net_room.receive_from("first", now, &first_ping),
+            Err(ReceiveError::Replayed(0))
+        ));
+
+        let unnumbered_ping = ClientPing {
+            term: 0,
+            knowledge: 3,
+            has_connection_to_leader: false,
+        }
+        .to_session_datagram(view.session_token)
+        .unwrap();
+        assert!(matches!(
+            net_room.receive_from("first", now, &unnumbered_ping),
+            Err(ReceiveError::MissingSequence)
+        ));
+
+        let connection_id = view.own_index.unwrap();
+        let connection = net_room.connections.get(connection_id).unwrap();
+        assert_eq!(connection.stats.packets_replayed, 1);
+        assert_eq!(connection.stats.packets_received, 3);
+        let connection = net_room.room.connections.get(&connection_id).unwrap();
+        assert_eq!(connection.knowledge, 1);
+    }
+
+    #[test]
+    fn ping_and_broadcast() {
+        let mut net_room = NetRoom::new();
+        let now = Instant::now();
+
+        let mut first_view = join(&mut net_room, "first", now);
+        let mut second_view = join(&mut net_room, "second", now);
+        net_room
+            .receive_from("first", now, &first_view.ping(42, false, now).unwrap())
+            .unwrap();
+        net_room
+            .receive_from("second", now, &second_view.ping(42, false, now).unwrap())
+            .unwrap();
+        assert_eq!(net_room.room.connections.len(), 2);
+
+        net_room.queue_broadcast(now).unwrap();
+        let outgoing = net_room.drain_outgoing();
+        assert_eq!(outgoing.len(), 2);
+        assert!(net_room.drain_outgoing().is_empty());
+
+        let (_, octets) = outgoing
+            .into_iter()
+            .find(|(address, _)| *address == "first")
+            .unwrap();
+        first_view
+            .receive(0, now, &mut InOctetStream::new(octets))
+            .unwrap();
+        assert_eq!(first_view.own_client_info().unwrap().knowledge, 42);
+
+        let first_connection_id = first_view.own_index.unwrap();
+        let stats = net_room.connections.get(first_connection_id).unwrap().stats;
+        assert_eq!(stats.packets_received, 2);
+        assert_eq!(stats.packets_sent, 2);
+    }
+
+    #[test]
+    fn reject_garbage_without_creating_connection() {
+        let mut net_room = NetRoom::new();
+
+        assert!(net_room
+            .receive_from("first", Instant::now(), &[0xde, 0xad])
+            .is_err());
+        assert!(net_room.room.connections.is_empty());
+        assert!(net_room.connections.is_empty());
+    }
+
+    #[test]
+    fn rate_limit_connections_and_addresses() {
+        let mut net_room = NetRoom::new();
+        net_room.config.rate_limit = Some(RateLimit {
+            datagrams_per_second: 10,
+            burst: 2,
+        });
+        let clock = ManualClock::default();
+
+        let mut view = join(&mut net_room, "first", clock.now());
+        for knowledge in 0..2 {
+            net_room
+                .receive_from(
+                    "first",
+                    clock.now(),
+                    &view.ping(knowledge, false, clock.now()).unwrap(),
+                )
+                .unwrap();
+        }
+        assert!(matches!(
+            net_room.receive_from(
+                "first",
+                clock.now(),
+                &view.ping(2, false, clock.now()).unwrap()
+            ),
+            Err(ReceiveError::RateLimited)
+        ));
+
+        for _ in 0..2 {
+            assert!(matches!(
+                net_room.receive_from("second", clock.now(), &[0x00]),
+                Err(ReceiveError::Truncated)
+            ));
+        }
+        assert!(matches!(
+            net_room.receive_from("second", clock.now(), &[0x00]),
+            Err(ReceiveError::RateLimited)
+        ));
+
+        let connection_id = view.own_index.unwrap();
+        let connection = net_room.connections.get(connection_id).unwrap();
+        assert_eq!(connection.stats.packets_rate_limited, 1);
+        assert_eq!(net_room.rate_limited, 2);
+
+        clock.advance(Duration::from_millis(100));
+        net_room
+            .receive_from(
+                "first",
+                clock.now(),
+                &view.ping(3, false, clock.now()).unwrap(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn reject_joins_when_full() {
+        let mut net_room = NetRoom::new();
+        net_room.config.max_connections = 1;
+        let now = Instant::now();
+        join(&mut net_room, "first", now);
+
+        assert!(matches!(
+            net_room.receive_from(
+                "second",
+                now,
+                &ClientRoomView::new().join_request().unwrap()
+            ),
+            Err(ReceiveError::RoomFull)
+        ));
+        assert_eq!(net_room.connections.len(), 1);
+        assert_eq!(net_room.room.connections.len(), 1);
+    }
+
+    #[test]
+    fn stats_track_traffic_and_rtt() {
+        let mut net_room = NetRoom::new();
+        let clock = ManualClock::default();
+        let mut view = join(&mut net_room, "first", clock.now());
+        let connection_id = view.own_index.unwrap();
+
+        clock.advance(Duration::from_millis(100));
+        net_room.queue_broadcast(clock.now()).unwrap();
+        clock.advance(Duration::from_millis(10));
+        for (_, octets) in net_room.drain_outgoing() {
+            view.receive(0, clock.now(), &mut InOctetStream::new(octets))
+                .unwrap();
+        }
+        clock.advance(Duration::from_millis(5));
+        let ping = view.ping(1, false, clock.now()).unwrap();
+        clock.advance(Duration::from_millis(10));
+        net_room.receive_from("first", clock.now(), &ping).unwrap();
+
+        assert!(matches!(
+            net_room.receive_from("first", clock.now(), &[0x00]),
+            Err(ReceiveError::Truncated)
+        ));
+
+        let stats = net_room.stats(connection_id).unwrap();
+        assert_eq!(stats.rtt, Some(Duration::from_millis(20)));
+        assert_eq!(stats.decode_errors, 1);
+        assert_eq!(stats.packets_received, 2);
+        assert_eq!(stats.packets_sent, 2);
+        assert_eq!(stats.last_received_at, Some(clock.now()));
+        assert_eq!(net_room.all_stats(), vec![(connection_id, stats)]);
+    }
+
+    #[test]
+    fn drop_silent_connections() {
+        let clock = ManualClock::default();
+        let mut net_room = NetRoom::new();
+        net_room.config.connection_timeout = Duration::from_secs(2);
+
+        let first_connection_id = join(&mut net_room, "first", clock.now()).own_index.unwrap();
+        let mut second_view = join(&mut net_room, "second", clock.now());
+        let second_connection_id = second_view.own_index.unwrap();
+
+        clock.advance(Duration::from_secs(2));
+        assert!(net_room.tick(clock.now()).is_empty());
+        net_room
+            .receive_from(
+                "second",
+                clock.now(),
+                &second_view.ping(0, false, clock.now()).unwrap(),
+            )
+            .unwrap();
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(net_room.tick(clock.now()), vec![first_connection_id]);
+        assert!(!net_room.room.connections.contains_key(&first_connection_id));
+        assert_eq!(net_room.connections.connection_id(&"first"), None);
+        assert_eq!(
+            net_room.connections.connection_id(&"second"),
+            Some(second_connection_id)
+        );
+
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(net_room.tick(clock.now()), vec![second_connection_id]);
+        assert!(net_room.room.connections.is_empty());
+    }
+
+    #[cfg(feature = "auth")]
+    fn authenticated_room() -> NetRoom<&'static str> {
+        use crate::NetRoomConfig;
+
+        NetRoom::with_config(NetRoomConfig {
+            room_key: Some([0x42; 32]),
+            ..NetRoomConfig::default()
+        })
+    }
+
+    #[cfg(feature = "auth")]
+    fn join_with_room_key(
+        net_room: &mut NetRoom<&'static str>,
+        address: &'static str,
+        room_key: crate::RoomKey,
+        now: Instant,
+    ) -> Result<ClientRoomView, ReceiveError> {
+        let mut view = ClientRoomView::with_room_key(room_key);
+        net_room.receive_from(address, now, &view.join_request().unwrap())?;
+        for (_, octets) in net_room
+            .drain_outgoing()
+            .into_iter()
+            .filter(|(receiver, _)| *receiver == address)
+        {
+            view.receive(0, now, &mut InOctetStream::new(octets))?;
+        }
+        Ok(view)
+    }
+
+    #[test]
+    #[cfg(feature = "auth")]
+    fn authenticated_join_and_ping() {
+        let mut net_room = authenticated_room();
+        let now = Instant::now();
+        let mut view = join_with_room_key(&mut net_room, "first", [0x42; 32], now).unwrap();
+
+        net_room
+            .receive_from("first", now, &view.ping(42, false, now).unwrap())
+            .unwrap();
+        net_room.queue_broadcast(now).unwrap();
+        for (_, octets) in net_room.drain_outgoing() {
+            view.receive(0, now, &mut InOctetStream::new(octets))
+                .unwrap();
+        }
+        assert_eq!(view.own_client_info().unwrap().knowledge, 42);
+    }
+
+    #[test]
+    #[cfg(feature = "auth")]
+    fn reject_wrong_room_key() {
+        let mut net_room = authenticated_room();
+        let now = Instant::now();
+
+        assert!(matches!(
+            join_with_room_key(&mut net_room, "first", [0x43; 32], now),
+            Err(ReceiveError::InvalidMac)
+        ));
+        let unauthenticated_join = ClientRoomView::new().join_request().unwrap();
+        assert!(matches!(
+            net_room.receive_from("first", now, &unauthenticated_join),
+            Err(ReceiveError::InvalidMac)
+        ));
+        assert!(net_room.connections.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "auth")]
+    fn reject_ping_forged_by_another_member() {
+        let mut net_room = authenticated_room();
+        let now = Instant::now();
+        let first = join_with_room_key(&mut net_room, "first", [0x42; 32], now).unwrap();
+        let mut second = join_with_room_key(&mut net_room, "second", [0x42; 32], now).unwrap();
+
+        // Knowing the session token is not enough without seeing the join accept of the connection
+        second.session_token = first.session_token;
+        let forged = second.ping(42, false, now).unwrap();
+        assert!(matches!(
+            net_room.receive_from("first", now, &forged),
+            Err(ReceiveError::InvalidMac | ReceiveError::DecryptionFailed)
+        ));
+        let connection_id = first.own_index.unwrap();
+        let connection = net_room.room.connections.get(&connection_id).unwrap();
+        assert_eq!(connection.knowledge, 0);
+    }
+
+    #[test]
+    #[cfg(all(feature = "auth", not(feature = "encryption")))]
+    fn reject_tampered_ping() {
+        use flood_rs::OutOctetStream;
+
+        use crate::datagram::{finish_datagram, without_checksum};
+
+        let mut net_room = authenticated_room();
+        let now = Instant::now();
+        let mut view = join_with_room_key(&mut net_room, "first", [0x42; 32], now).unwrap();
+        let octets = view.ping(42, false, now).unwrap();
+
+        let mut stream = OutOctetStream::new();
+        stream.data = without_checksum(&octets).to_vec();
+        let knowledge_offset = stream.data.len() - 32 - 2;
+        stream.data[knowledge_offset] ^= 0x01;
+        let tampered = finish_datagram(stream).unwrap();
+
+        assert!(matches!(
+            net_room.receive_from("first", now, &tampered),
+            Err(ReceiveError::InvalidMac)
+        ));
+        let connection_id = view.own_index.unwrap();
+        let connection = net_room.room.connections.get(&connection_id).unwrap();
+        assert_eq!(connection.knowledge, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn reject_tampered_encrypted_ping() {
+        use flood_rs::OutOctetStream;
+
+        use crate::datagram::{finish_datagram, without_checksum, DatagramHeader};
+
+        let mut net_room = authenticated_room();
+        let now = Instant::now();
+        let mut view = join_with_room_key(&mut net_room, "first", [0x42; 32], now).unwrap();
+        let octets = view.ping(42, false, now).unwrap();
+
+        let mut stream = OutOctetStream::new();
+        stream.data = without_checksum(&octets).to_vec();
+        let last = stream.data.len() - 1;
+        stream.data[last] ^= 0x01;
+        let tampered = finish_datagram(stream).unwrap();
+
+        assert!(matches!(
+            net_room.receive_from("first", now, &tampered),
+            Err(ReceiveError::DecryptionFailed)
+        ));
+        let header = DatagramHeader {
+            session_token: view.session_token,
+            sequence: Some(100),
+        };
+        let unencrypted_ping = ClientPing {
+            term: 0,
+            knowledge: 42,
+            has_connection_to_leader: false,
+        }
+        .write_datagram(&header, None)
+        .unwrap();
+        assert!(matches!(
+            net_room.receive_from("first", now, &unencrypted_ping),
+            Err(ReceiveError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "auth")]
+    fn reject_replayed_room_info() {
+        let mut net_room = authenticated_room();
+        let now = Instant::now();
+        let mut view = join_with_room_key(&mut net_room, "first", [0x42; 32], now).unwrap();
+
+        net_room.queue_broadcast(now).unwrap();
+        let (_, room_info) = net_room.drain_outgoing().pop().unwrap();
+        view.receive(0, now, &mut InOctetStream::new(room_info.clone()))
+            .unwrap();
+        assert!(matches!(
+            view.receive(0, now, &mut InOctetStream::new(room_info)),
+            Err(ReceiveError::Replayed(0))
+        ));
+    }
+}
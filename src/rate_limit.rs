@@ -0,0 +1,105 @@
+/*----------------------------------------------------------------------------------------------------------
+ *  Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/conclave-room-net-rs
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *--------------------------------------------------------------------------------------------------------*/
+//! Token bucket rate limiting of received datagrams
+use std::time::Instant;
+
+/// Used by [`crate::NetRoomConfig::default`]. Well above what a client pinging a few times a second
+/// needs, while a flood is cut off after the first `burst` datagrams.
+pub const DEFAULT_RATE_LIMIT: RateLimit = RateLimit {
+    datagrams_per_second: 60,
+    burst: 30,
+};
+
+/// How many datagrams a single sender may send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// How fast the bucket refills.
+    pub datagrams_per_second: u32,
+    /// How many datagrams can be received at once, after the sender has been quiet for a while.
+    pub burst: u32,
+}
+
+/// The tokens left for a single sender. Starts out full.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TokenBucket {
+    used: f64,
+    updated_at: Option<Instant>,
+}
+
+impl TokenBucket {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn used_at(&self, limit: &RateLimit, now: Instant) -> f64 {
+        let Some(updated_at) = self.updated_at else {
+            return 0.0;
+        };
+        let refilled = now.saturating_duration_since(updated_at).as_secs_f64()
+            * f64::from(limit.datagrams_per_second);
+        (self.used - refilled).max(0.0)
+    }
+
+    /// Takes a token for a datagram received at `now`. Returns false if the bucket is empty.
+    pub fn try_take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        let used = self.used_at(limit, now);
+        self.updated_at = Some(now);
+        if used + 1.0 > f64::from(limit.burst) {
+            self.used = used;
+            return false;
+        }
+        self.used = used + 1.0;
+        true
+    }
+
+    /// True if the bucket has refilled completely, so forgetting it makes no difference.
+    pub fn is_full(&self, limit: &RateLimit, now: Instant) -> bool {
+        self.used_at(limit, now) <= 0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::rate_limit::{RateLimit, TokenBucket};
+
+    const LIMIT: RateLimit = RateLimit {
+        datagrams_per_second: 10,
+        burst: 3,
+    };
+
+    #[test]
+    fn burst_then_refill() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new();
+        assert!(bucket.is_full(&LIMIT, now));
+
+        for _ in 0..LIMIT.burst {
+            assert!(bucket.try_take(&LIMIT, now));
+        }
+        assert!(!bucket.try_take(&LIMIT, now));
+        assert!(!bucket.is_full(&LIMIT, now));
+
+        let later = now + Duration::from_millis(100);
+        assert!(bucket.try_take(&LIMIT, later));
+        assert!(!bucket.try_take(&LIMIT, later));
+
+        assert!(bucket.is_full(&LIMIT, later + Duration::from_millis(300)));
+    }
+
+    #[test]
+    fn never_holds_more_than_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new();
+        assert!(bucket.try_take(&LIMIT, now));
+
+        let later = now + Duration::from_secs(60);
+        for _ in 0..LIMIT.burst {
+            assert!(bucket.try_take(&LIMIT, later));
+        }
+        assert!(!bucket.try_take(&LIMIT, later));
+    }
+}
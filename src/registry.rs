@@ -0,0 +1,175 @@
+/*----------------------------------------------------------------------------------------------------------
+ *  Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/conclave-room-net-rs
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *--------------------------------------------------------------------------------------------------------*/
+//! Maps transport addresses to room connections
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io;
+use std::time::Instant;
+
+use conclave_room::{ConnectionIndex, Room};
+
+#[cfg(feature = "auth")]
+use crate::auth::ConnectionKeys;
+use crate::connection::NetworkConnection;
+use crate::session::new_session_token;
+
+/// Keeps track of which transport address (usually a `SocketAddr`) belongs to which connection.
+#[derive(Debug)]
+pub struct ConnectionRegistry<A> {
+    connection_ids: HashMap<A, ConnectionIndex>,
+    connections: HashMap<ConnectionIndex, NetworkConnection<A>>,
+}
+
+impl<A> Default for ConnectionRegistry<A> {
+    fn default() -> Self {
+        Self {
+            connection_ids: HashMap::new(),
+            connections: HashMap::new(),
+        }
+    }
+}
+
+impl<A: Copy + Eq + Hash> ConnectionRegistry<A> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connection_id(&self, address: &A) -> Option<ConnectionIndex> {
+        self.connection_ids.get(address).copied()
+    }
+
+    pub fn address(&self, connection_id: ConnectionIndex) -> Option<A> {
+        self.connections
+            .get(&connection_id)
+            .map(|connection| connection.address)
+    }
+
+    pub fn get(&self, connection_id: ConnectionIndex) -> Option<&NetworkConnection<A>> {
+        self.connections.get(&connection_id)
+    }
+
+    pub fn get_mut(&mut self, connection_id: ConnectionIndex) -> Option<&mut NetworkConnection<A>> {
+        self.connections.get_mut(&connection_id)
+    }
+
+    /// Returns the connection for `address`, creating it in the room with a new session token (and
+    /// connection keys, with the `auth` feature) on first contact. The boolean is true if the
+    /// connection was created by this call.
+    pub fn get_or_create(
+        &mut self,
+        address: A,
+        room: &mut Room,
+        now: Instant,
+    ) -> io::Result<(ConnectionIndex, bool)> {
+        if let Some(connection_id) = self.connection_id(&address) {
+            return Ok((connection_id, false));
+        }
+
+        let session_token = new_session_token()?;
+        #[cfg(feature = "auth")]
+        let keys = ConnectionKeys::generate()?;
+        let connection_id = room.create_connection(now);
+        let connection = NetworkConnection::new(connection_id, address, session_token, now);
+        #[cfg(feature = "auth")]
+        let connection = connection.with_keys(keys);
+        self.connection_ids.insert(address, connection_id);
+        self.connections.insert(connection_id, connection);
+        Ok((connection_id, true))
+    }
+
+    /// Forgets `connection_id` and its address. The room itself is not touched.
+    pub fn remove(&mut self, connection_id: ConnectionIndex) -> Option<NetworkConnection<A>> {
+        let connection = self.connections.remove(&connection_id)?;
+        self.connection_ids.remove(&connection.address);
+        Some(connection)
+    }
+
+    /// Forgets every connection that is no longer part of `room`, and returns them.
+    pub fn remove_dropped(&mut self, room: &Room) -> Vec<NetworkConnection<A>> {
+        let dropped: Vec<ConnectionIndex> = self
+            .connections
+            .keys()
+            .filter(|connection_id| !room.connections.contains_key(connection_id))
+            .copied()
+            .collect();
+
+        dropped
+            .into_iter()
+            .filter_map(|connection_id| self.remove(connection_id))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &NetworkConnection<A>> {
+        self.connections.values()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut NetworkConnection<A>> {
+        self.connections.values_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use conclave_room::Room;
+
+    use crate::registry::ConnectionRegistry;
+
+    #[test]
+    fn create_on_first_contact() {
+        let mut room = Room::new();
+        let mut registry = ConnectionRegistry::new();
+        let now = Instant::now();
+
+        let (first_connection_id, created) =
+            registry.get_or_create("first", &mut room, now).unwrap();
+        assert!(created);
+        let (second_connection_id, created) =
+            registry.get_or_create("second", &mut room, now).unwrap();
+        assert!(created);
+        assert_ne!(first_connection_id, second_connection_id);
+
+        let (connection_id, created) = registry.get_or_create("first", &mut room, now).unwrap();
+        assert!(!created);
+        assert_eq!(connection_id, first_connection_id);
+        assert_eq!(registry.address(second_connection_id), Some("second"));
+        assert_eq!(
+            registry.get(second_connection_id).unwrap().id,
+            second_connection_id
+        );
+        assert_eq!(room.connections.len(), 2);
+    }
+
+    #[test]
+    fn remove_dropped_connections() {
+        let mut room = Room::new();
+        let mut registry = ConnectionRegistry::new();
+        let now = Instant::now();
+
+        let (first_connection_id, _) = registry.get_or_create("first", &mut room, now).unwrap();
+        let (second_connection_id, _) = registry.get_or_create("second", &mut room, now).unwrap();
+        room.connections.remove(&first_connection_id);
+
+        let dropped = registry.remove_dropped(&room);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].id, first_connection_id);
+        assert_eq!(dropped[0].address, "first");
+        assert_eq!(registry.connection_id(&"first"), None);
+        assert_eq!(
+            registry.connection_id(&"second"),
+            Some(second_connection_id)
+        );
+        assert_eq!(registry.len(), 1);
+    }
+}
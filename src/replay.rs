@@ -0,0 +1,83 @@
+/*----------------------------------------------------------------------------------------------------------
+ *  Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/conclave-room-net-rs
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *--------------------------------------------------------------------------------------------------------*/
+//! Detection of replayed datagrams
+/// How far behind the highest received sequence number a datagram may arrive and still be accepted.
+pub const REPLAY_WINDOW_SIZE: u64 = 64;
+
+/// Remembers which of the last [`REPLAY_WINDOW_SIZE`] sequence numbers have been received, so
+/// datagrams can arrive out of order but never twice.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayWindow {
+    highest: Option<u64>,
+    /// Bit `n` is set if `highest - n` has been received.
+    received: u64,
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// True until the first sequence number has been accepted.
+    pub fn is_empty(&self) -> bool {
+        self.highest.is_none()
+    }
+
+    /// Marks `sequence` as received. Returns false if it was received before, or is too old to tell.
+    pub fn accept(&mut self, sequence: u64) -> bool {
+        let Some(highest) = self.highest else {
+            self.highest = Some(sequence);
+            self.received = 1;
+            return true;
+        };
+
+        if sequence > highest {
+            let shift = sequence - highest;
+            self.received = if shift < REPLAY_WINDOW_SIZE {
+                (self.received << shift) | 1
+            } else {
+                1
+            };
+            self.highest = Some(sequence);
+            return true;
+        }
+
+        let age = highest - sequence;
+        if age >= REPLAY_WINDOW_SIZE || self.received & (1 << age) != 0 {
+            return false;
+        }
+        self.received |= 1 << age;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::replay::{ReplayWindow, REPLAY_WINDOW_SIZE};
+
+    #[test]
+    fn accept_each_sequence_once() {
+        let mut window = ReplayWindow::new();
+        assert!(window.is_empty());
+        assert!(window.accept(10));
+        assert!(!window.is_empty());
+        assert!(!window.accept(10));
+        assert!(window.accept(8));
+        assert!(window.accept(11));
+        assert!(!window.accept(8));
+        assert!(window.accept(9));
+        assert!(!window.accept(11));
+    }
+
+    #[test]
+    fn reject_too_old() {
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(REPLAY_WINDOW_SIZE + 10));
+        assert!(!window.accept(10));
+        assert!(window.accept(11));
+        assert!(window.accept(1000));
+        assert!(!window.accept(REPLAY_WINDOW_SIZE + 10));
+    }
+}
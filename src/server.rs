@@ -0,0 +1,379 @@
+/*----------------------------------------------------------------------------------------------------------
+ *  Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/conclave-room-net-rs
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *--------------------------------------------------------------------------------------------------------*/
+//! A room server on top of a [`DatagramTransport`]
+use std::io;
+use std::net::ToSocketAddrs;
+use std::time::{Duration, Instant};
+
+use conclave_room::ConnectionIndex;
+
+use crate::clock::{Clock, SystemClock};
+use crate::net_room::NetRoom;
+use crate::transport::{is_peer_error, DatagramTransport, UdpTransport, MAX_DATAGRAM_SIZE};
+use crate::ReceiveError;
+
+/// How many datagrams a single [`RoomServer::update`] receives at most. The rest wait in the
+/// transport for the next update, so a flood can not hold off the ticks and broadcasts.
+pub const MAX_DATAGRAMS_PER_UPDATE: usize = 256;
+
+/// What happened during a [`RoomServer::update`].
+#[derive(Debug)]
+pub struct UpdateReport<A> {
+    /// Datagrams that were rejected, and why.
+    pub rejected: Vec<(A, ReceiveError)>,
+    /// Connections that left, timed out or were dropped by the room.
+    pub dropped: Vec<ConnectionIndex>,
+    /// Datagrams that could not be sent to a peer, see [`is_peer_error`].
+    pub send_errors: Vec<(A, io::Error)>,
+    /// Receive errors caused by a peer, usually reporting that an earlier datagram did not
+    /// arrive.
+    pub receive_errors: Vec<io::Error>,
+}
+
+pub struct RoomServer<T: DatagramTransport, C: Clock = SystemClock> {
+    transport: T,
+    clock: C,
+    pub net_room: NetRoom<T::Address>,
+    broadcast_interval: Duration,
+    next_broadcast_at: Option<Instant>,
+    buffer: Vec<u8>,
+}
+
+pub type UdpRoomServer = RoomServer<UdpTransport>;
+
+impl UdpRoomServer {
+    pub fn bind(address: impl ToSocketAddrs, broadcast_interval: Duration) -> io::Result<Self> {
+        Ok(Self::new(UdpTransport::bind(address)?, broadcast_interval))
+    }
+}
+
+impl<T: DatagramTransport> RoomServer<T> {
+    pub fn new(transport: T, broadcast_interval: Duration) -> Self {
+        Self::with_clock(transport, broadcast_interval, SystemClock)
+    }
+}
+
+impl<T: DatagramTransport, C: Clock> RoomServer<T, C> {
+    pub fn with_clock(transport: T, broadcast_interval: Duration, clock: C) -> Self {
+        Self {
+            transport,
+            clock,
+            net_room: NetRoom::new(),
+            broadcast_interval,
+            next_broadcast_at: None,
+            buffer: vec![0u8; MAX_DATAGRAM_SIZE],
+        }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Sends the personalized room info to every known peer.
+    /// Returns the peers that could not be sent to, see [`RoomServer::flush`].
+    pub fn broadcast(&mut self) -> io::Result<Vec<(T::Address, io::Error)>> {
+        self.net_room
+            .queue_broadcast(self.clock.now())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        self.flush()
+    }
+
+    /// Sends every queued datagram, like the join accepts.
+    /// A peer that can not be sent to does not stop the others, it is returned together with
+    /// the error and counted in its [`crate::ConnectionStats::send_errors`].
+    pub fn flush(&mut self) -> io::Result<Vec<(T::Address, io::Error)>> {
+        let mut send_errors = Vec::new();
+        for (address, octets) in self.net_room.drain_outgoing() {
+            match self.transport.send_to(&octets, address) {
+                Ok(()) => {}
+                Err(err) if is_peer_error(&err) => {
+                    self.net_room.on_send_failed(&address);
+                    send_errors.push((address, err));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(send_errors)
+    }
+
+    /// Handles the waiting datagrams, up to [`MAX_DATAGRAMS_PER_UPDATE`], drops silent connections,
+    /// broadcasts if it is time to and sends everything that was queued. Never blocks.
+    pub fn update(&mut self) -> io::Result<UpdateReport<T::Address>> {
+        self.update_after(Duration::ZERO)
+    }
+
+    /// Like [`RoomServer::update`], but first waits for a datagram until the next broadcast is due.
+    pub fn wait_and_update(&mut self) -> io::Result<UpdateReport<T::Address>> {
+        let timeout = match self.next_broadcast_at {
+            Some(next_broadcast_at) => {
+                next_broadcast_at.saturating_duration_since(self.clock.now())
+            }
+            None => Duration::ZERO,
+        };
+        self.update_after(timeout)
+    }
+
+    /// Only fails if the transport itself does, errors caused by a single peer end up in the
+    /// report.
+    fn update_after(&mut self, timeout: Duration) -> io::Result<UpdateReport<T::Address>> {
+        let mut rejected = Vec::new();
+        let mut receive_errors = Vec::new();
+        for count in 0..MAX_DATAGRAMS_PER_UPDATE {
+            let received = if count == 0 {
+                self.transport.recv_timeout(&mut self.buffer, timeout)
+            } else {
+                self.transport.recv_from(&mut self.buffer)
+            };
+            match received {
+                Ok(Some((size, address))) => {
+                    let now = self.clock.now();
+                    let octets = &self.buffer[..size];
+                    if let Err(err) = self.net_room.receive_from(address, now, octets) {
+                        rejected.push((address, err));
+                    }
+                }
+                Ok(None) => break,
+                Err(err) if is_peer_error(&err) => receive_errors.push(err),
+                Err(err) => return Err(err),
+            }
+        }
+
+        let now = self.clock.now();
+        let dropped = self.net_room.tick(now);
+
+        let broadcast_is_due = match self.next_broadcast_at {
+            Some(next_broadcast_at) => now >= next_broadcast_at,
+            None => true,
+        };
+        let send_errors = if broadcast_is_due {
+            self.next_broadcast_at = Some(now + self.broadcast_interval);
+            self.broadcast()?
+        } else {
+            self.flush()?
+        };
+
+        Ok(UpdateReport {
+            rejected,
+            dropped,
+            send_errors,
+            receive_errors,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::UdpSocket;
+    use std::time::{Duration, Instant};
+
+    use flood_rs::InOctetStream;
+
+    use crate::clock::{Clock, ManualClock};
+    use crate::server::{RoomServer, UdpRoomServer, MAX_DATAGRAMS_PER_UPDATE};
+    use crate::transport::{
+        DatagramTransport, MemoryAddress, MemoryNetwork, MemoryTransport, MAX_DATAGRAM_SIZE,
+    };
+    use crate::{ClientRoomView, ReceiveDatagram};
+
+    /// Fails to send to `refused`, like a socket asked to send to port 0.
+    struct RefusingTransport {
+        inner: MemoryTransport,
+        refused: Option<MemoryAddress>,
+    }
+
+    impl DatagramTransport for RefusingTransport {
+        type Address = MemoryAddress;
+
+        fn send_to(&mut self, datagram: &[u8], address: MemoryAddress) -> io::Result<()> {
+            if self.refused == Some(address) {
+                return Err(io::ErrorKind::InvalidInput.into());
+            }
+            self.inner.send_to(datagram, address)
+        }
+
+        fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, MemoryAddress)>> {
+            self.inner.recv_from(buffer)
+        }
+    }
+
+    #[test]
+    fn whole_room_in_memory() {
+        let network = MemoryNetwork::new();
+        let clock = ManualClock::default();
+        let mut server = RoomServer::with_clock(
+            network.endpoint(),
+            Duration::from_millis(100),
+            clock.clone(),
+        );
+        let server_address = server.transport().local_addr();
+        let mut clients = [network.endpoint(), network.endpoint()];
+
+        let mut views = [ClientRoomView::new(), ClientRoomView::new()];
+        for (client, view) in clients.iter_mut().zip(&mut views) {
+            client
+                .send_to(&view.join_request().unwrap(), server_address)
+                .unwrap();
+        }
+        assert!(server.update().unwrap().rejected.is_empty());
+
+        for (client, view) in clients.iter_mut().zip(&mut views) {
+            let datagrams = client.poll().unwrap();
+            assert_eq!(datagrams.len(), 2);
+            for (_, octets) in datagrams {
+                view.receive(0, clock.now(), &mut InOctetStream::new(octets))
+                    .unwrap();
+            }
+            assert_eq!(view.client_infos.len(), 2);
+            assert!(view.own_client_info().is_some());
+        }
+
+        for (index, (client, view)) in clients.iter_mut().zip(&mut views).enumerate() {
+            client
+                .send_to(
+                    &view.ping(index as u64, false, clock.now()).unwrap(),
+                    server_address,
+                )
+                .unwrap();
+        }
+        clock.advance(Duration::from_millis(50));
+        assert!(server.update().unwrap().rejected.is_empty());
+        assert!(clients[0].poll().unwrap().is_empty());
+
+        clock.advance(Duration::from_millis(50));
+        server.update().unwrap();
+        for (index, (client, view)) in clients.iter_mut().zip(&mut views).enumerate() {
+            let datagrams = client.poll().unwrap();
+            assert_eq!(datagrams.len(), 1);
+            for (_, octets) in datagrams {
+                view.receive(0, clock.now(), &mut InOctetStream::new(octets))
+                    .unwrap();
+            }
+            assert_eq!(view.own_client_info().unwrap().knowledge, index as u64);
+        }
+
+        clients[1]
+            .send_to(&views[1].leave().unwrap(), server_address)
+            .unwrap();
+        let report = server.update().unwrap();
+        assert_eq!(report.dropped, vec![views[1].own_index.unwrap()]);
+
+        clock.advance(server.net_room.config.connection_timeout);
+        let report = server.update().unwrap();
+        assert_eq!(report.dropped, vec![views[0].own_index.unwrap()]);
+        assert!(server.net_room.room.connections.is_empty());
+    }
+
+    #[test]
+    fn receive_a_bounded_number_of_datagrams_per_update() {
+        let network = MemoryNetwork::new();
+        let mut server = RoomServer::new(network.endpoint(), Duration::from_millis(100));
+        let server_address = server.transport().local_addr();
+        let mut client = network.endpoint();
+        for _ in 0..MAX_DATAGRAMS_PER_UPDATE + 1 {
+            client.send_to(&[0x00], server_address).unwrap();
+        }
+
+        let report = server.update().unwrap();
+        assert_eq!(report.rejected.len(), MAX_DATAGRAMS_PER_UPDATE);
+        let report = server.update().unwrap();
+        assert_eq!(report.rejected.len(), 1);
+        assert!(server.update().unwrap().rejected.is_empty());
+    }
+
+    #[test]
+    fn keep_serving_when_a_peer_can_not_be_sent_to() {
+        let network = MemoryNetwork::new();
+        let mut server = RoomServer::new(
+            RefusingTransport {
+                inner: network.endpoint(),
+                refused: None,
+            },
+            Duration::from_millis(100),
+        );
+        let server_address = server.transport().inner.local_addr();
+        let mut clients = [network.endpoint(), network.endpoint()];
+        for client in &mut clients {
+            client
+                .send_to(
+                    &ClientRoomView::new().join_request().unwrap(),
+                    server_address,
+                )
+                .unwrap();
+        }
+
+        server.transport.refused = Some(clients[0].local_addr());
+        let report = server.update().unwrap();
+        assert_eq!(report.send_errors.len(), 2);
+        assert!(report
+            .send_errors
+            .iter()
+            .all(|(address, _)| *address == clients[0].local_addr()));
+        assert!(clients[0].poll().unwrap().is_empty());
+        assert_eq!(clients[1].poll().unwrap().len(), 2);
+
+        let all_stats = server.net_room.all_stats();
+        assert_eq!(
+            all_stats
+                .iter()
+                .map(|(_, stats)| stats.send_errors)
+                .collect::<Vec<_>>(),
+            vec![2, 0]
+        );
+    }
+
+    #[test]
+    fn ping_and_receive_room_info_over_localhost() {
+        let mut server = UdpRoomServer::bind("127.0.0.1:0", Duration::from_millis(5)).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(1)))
+            .unwrap();
+
+        let server_address = server.transport().local_addr().unwrap();
+        let mut view = ClientRoomView::new();
+        client
+            .send_to(&view.join_request().unwrap(), server_address)
+            .unwrap();
+
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut has_pinged = false;
+        for _ in 0..100 {
+            assert!(server.update().unwrap().rejected.is_empty());
+            if let Ok((size, _)) = client.recv_from(&mut buffer) {
+                view.receive(
+                    0,
+                    Instant::now(),
+                    &mut InOctetStream::new(buffer[..size].to_vec()),
+                )
+                .unwrap();
+                if !has_pinged && view.session_token.is_some() {
+                    client
+                        .send_to(
+                            &view.ping(42, false, Instant::now()).unwrap(),
+                            server_address,
+                        )
+                        .unwrap();
+                    has_pinged = true;
+                }
+                if view
+                    .own_client_info()
+                    .is_some_and(|client_info| client_info.knowledge == 42)
+                {
+                    break;
+                }
+            }
+        }
+
+        let own_client_info = view.own_client_info().unwrap();
+        assert_eq!(own_client_info.knowledge, 42);
+    }
+}
@@ -0,0 +1,40 @@
+/*----------------------------------------------------------------------------------------------------------
+ *  Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/conclave-room-net-rs
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *--------------------------------------------------------------------------------------------------------*/
+//! Session tokens that tie datagrams to the connection that joined
+use std::io;
+
+/// Issued to a connection in the join accept. Every datagram from a joined client carries it, so
+/// knowing the (sequential) connection index or source address is not enough to speak for it.
+pub type SessionToken = u64;
+
+/// Chosen by the client for every join request it sends, and echoed in the join accept. Tells the
+/// client that the join accept answers its latest join request and is not a replay.
+pub type JoinNonce = u64;
+
+fn random_u64() -> io::Result<u64> {
+    let mut octets = [0u8; 8];
+    getrandom::getrandom(&mut octets)?;
+    Ok(u64::from_be_bytes(octets))
+}
+
+/// A new token from the random source of the operating system.
+pub(crate) fn new_session_token() -> io::Result<SessionToken> {
+    random_u64()
+}
+
+/// A new nonce from the random source of the operating system.
+pub(crate) fn new_join_nonce() -> io::Result<JoinNonce> {
+    random_u64()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::session::new_session_token;
+
+    #[test]
+    fn tokens_differ() {
+        assert_ne!(new_session_token().unwrap(), new_session_token().unwrap());
+    }
+}
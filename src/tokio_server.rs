@@ -0,0 +1,209 @@
+/*----------------------------------------------------------------------------------------------------------
+ *  Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/conclave-room-net-rs
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *--------------------------------------------------------------------------------------------------------*/
+//! A room server running as a tokio task
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use conclave_room::ConnectionIndex;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::task::JoinHandle;
+
+use crate::clock::{Clock, SystemClock};
+use crate::net_room::NetRoom;
+use crate::transport::{is_peer_error, MAX_DATAGRAM_SIZE};
+use crate::ReceiveError;
+
+pub struct TokioRoomServer<C: Clock = SystemClock> {
+    socket: UdpSocket,
+    clock: C,
+    pub net_room: NetRoom<SocketAddr>,
+    broadcast_interval: Duration,
+}
+
+impl TokioRoomServer {
+    pub async fn bind(
+        address: impl ToSocketAddrs,
+        broadcast_interval: Duration,
+    ) -> io::Result<Self> {
+        Self::from_socket(UdpSocket::bind(address).await?, broadcast_interval)
+    }
+
+    pub fn from_socket(socket: UdpSocket, broadcast_interval: Duration) -> io::Result<Self> {
+        Self::with_clock(socket, broadcast_interval, SystemClock)
+    }
+}
+
+impl<C: Clock> TokioRoomServer<C> {
+    /// The clock only stamps received datagrams, broadcasts are still paced by tokio time.
+    /// A zero `broadcast_interval` is rejected with [`io::ErrorKind::InvalidInput`].
+    pub fn with_clock(
+        socket: UdpSocket,
+        broadcast_interval: Duration,
+        clock: C,
+    ) -> io::Result<Self> {
+        if broadcast_interval.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "broadcast interval must not be zero",
+            ));
+        }
+        Ok(Self {
+            socket,
+            clock,
+            net_room: NetRoom::new(),
+            broadcast_interval,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Sends the personalized room info to every known peer.
+    /// Returns the peers that could not be sent to, see [`TokioRoomServer::flush`].
+    pub async fn broadcast(&mut self) -> io::Result<Vec<(SocketAddr, io::Error)>> {
+        self.net_room
+            .queue_broadcast(self.clock.now())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        self.flush().await
+    }
+
+    /// Sends every queued datagram, like the join accepts.
+    /// A peer that can not be sent to does not stop the others, it is returned together with
+    /// the error and counted in its [`crate::ConnectionStats::send_errors`].
+    pub async fn flush(&mut self) -> io::Result<Vec<(SocketAddr, io::Error)>> {
+        let mut send_errors = Vec::new();
+        for (address, octets) in self.net_room.drain_outgoing() {
+            match self.socket.send_to(&octets, address).await {
+                Ok(_) => {}
+                Err(err) if is_peer_error(&err) => {
+                    self.net_room.on_send_failed(&address);
+                    send_errors.push((address, err));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(send_errors)
+    }
+
+    /// Receives datagrams and answers join requests, and drops silent connections and broadcasts on every interval tick,
+    /// until the socket fails. Errors caused by a single peer do not stop the server, failed
+    /// sends are counted in the stats of the connection.
+    /// Rejected datagrams are reported to `on_rejected` and dropped connections to `on_dropped`.
+    pub async fn run_with(
+        mut self,
+        mut on_rejected: impl FnMut(SocketAddr, ReceiveError),
+        mut on_dropped: impl FnMut(ConnectionIndex),
+    ) -> io::Result<()> {
+        let mut interval = tokio::time::interval(self.broadcast_interval);
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            tokio::select! {
+                received = self.socket.recv_from(&mut buffer) => {
+                    let (size, address) = match received {
+                        Ok(received) => received,
+                        Err(err) if is_peer_error(&err) => continue,
+                        Err(err) => return Err(err),
+                    };
+                    let octets = &buffer[..size];
+                    let now = self.clock.now();
+                    if let Err(err) = self.net_room.receive_from(address, now, octets) {
+                        on_rejected(address, err);
+                    }
+                    self.flush().await?;
+                }
+                _ = interval.tick() => {
+                    for connection_id in self.net_room.tick(self.clock.now()) {
+                        on_dropped(connection_id);
+                    }
+                    self.broadcast().await?;
+                }
+            }
+        }
+    }
+
+    pub async fn run(self) -> io::Result<()> {
+        self.run_with(|_, _| {}, |_| {}).await
+    }
+
+    /// Moves the server, and the room it owns, into a task on the current runtime.
+    pub fn spawn(self) -> JoinHandle<io::Result<()>>
+    where
+        C: Send + 'static,
+    {
+        tokio::spawn(self.run())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use flood_rs::InOctetStream;
+    use tokio::net::UdpSocket;
+
+    use crate::tokio_server::TokioRoomServer;
+    use crate::transport::MAX_DATAGRAM_SIZE;
+    use crate::{ClientRoomView, ReceiveDatagram};
+
+    #[tokio::test]
+    async fn ping_and_receive_room_info() {
+        let server = TokioRoomServer::bind("127.0.0.1:0", Duration::from_millis(5))
+            .await
+            .unwrap();
+        let server_address = server.local_addr().unwrap();
+        let task = server.spawn();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut view = ClientRoomView::new();
+        client
+            .send_to(&view.join_request().unwrap(), server_address)
+            .await
+            .unwrap();
+
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+        while !view
+            .own_client_info()
+            .is_some_and(|client_info| client_info.knowledge == 42)
+        {
+            let (size, _) =
+                tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buffer))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let had_joined = view.session_token.is_some();
+            view.receive(
+                0,
+                Instant::now(),
+                &mut InOctetStream::new(buffer[..size].to_vec()),
+            )
+            .unwrap();
+            if !had_joined && view.session_token.is_some() {
+                client
+                    .send_to(
+                        &view.ping(42, false, Instant::now()).unwrap(),
+                        server_address,
+                    )
+                    .await
+                    .unwrap();
+            }
+        }
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn reject_zero_broadcast_interval() {
+        let result = TokioRoomServer::bind("127.0.0.1:0", Duration::ZERO).await;
+        assert_eq!(
+            result.err().map(|err| err.kind()),
+            Some(std::io::ErrorKind::InvalidInput)
+        );
+    }
+}
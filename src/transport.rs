@@ -0,0 +1,310 @@
+/*----------------------------------------------------------------------------------------------------------
+ *  Copyright (c) Peter Bjorklund. All rights reserved. https://github.com/piot/conclave-room-net-rs
+ *  Licensed under the MIT License. See LICENSE in the project root for license information.
+ *--------------------------------------------------------------------------------------------------------*/
+//! Moves datagrams between peers
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The largest possible UDP payload, so a received datagram is never truncated.
+pub const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+/// True for errors caused by a single peer rather than by the socket, like an address the
+/// socket refuses to send to or an ICMP error reported for an earlier datagram. A full send
+/// buffer is included, the datagram is lost just like on the network.
+pub fn is_peer_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::InvalidInput
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::AddrNotAvailable
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::WouldBlock
+    )
+}
+
+pub trait DatagramTransport {
+    type Address: Copy + Eq + Hash + Debug;
+
+    fn send_to(&mut self, datagram: &[u8], address: Self::Address) -> io::Result<()>;
+
+    /// Never blocks. Returns `None` if no datagram is waiting.
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, Self::Address)>>;
+
+    /// Waits at most `timeout` for a datagram. Returns `None` if none arrived in time.
+    /// Transports that can not wait do not need to implement this, they never block.
+    fn recv_timeout(
+        &mut self,
+        buffer: &mut [u8],
+        _timeout: Duration,
+    ) -> io::Result<Option<(usize, Self::Address)>> {
+        self.recv_from(buffer)
+    }
+
+    /// Receives every datagram that is waiting, without blocking.
+    fn poll(&mut self) -> io::Result<Vec<(Self::Address, Vec<u8>)>> {
+        self.poll_timeout(Duration::ZERO)
+    }
+
+    /// Receives every datagram that is waiting, after waiting at most `timeout` for the first one.
+    fn poll_timeout(&mut self, timeout: Duration) -> io::Result<Vec<(Self::Address, Vec<u8>)>> {
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut datagrams = Vec::new();
+        let mut received = self.recv_timeout(&mut buffer, timeout)?;
+        while let Some((size, address)) = received {
+            datagrams.push((address, buffer[..size].to_vec()));
+            received = self.recv_from(&mut buffer)?;
+        }
+        Ok(datagrams)
+    }
+}
+
+/// A non-blocking [`UdpSocket`].
+#[derive(Debug)]
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn bind(address: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::from_socket(UdpSocket::bind(address)?)
+    }
+
+    pub fn from_socket(socket: UdpSocket) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl DatagramTransport for UdpTransport {
+    type Address = SocketAddr;
+
+    fn send_to(&mut self, datagram: &[u8], address: SocketAddr) -> io::Result<()> {
+        self.socket.send_to(datagram, address)?;
+        Ok(())
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        match self.socket.recv_from(buffer) {
+            Ok(received) => Ok(Some(received)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Blocks on the socket with a read timeout, it is non-blocking again afterwards.
+    fn recv_timeout(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<Option<(usize, SocketAddr)>> {
+        // A zero read timeout would block forever
+        if timeout.is_zero() {
+            return self.recv_from(buffer);
+        }
+
+        self.socket.set_nonblocking(false)?;
+        self.socket.set_read_timeout(Some(timeout))?;
+        let received = self.socket.recv_from(buffer);
+        self.socket.set_nonblocking(true)?;
+
+        match received {
+            Ok(received) => Ok(Some(received)),
+            Err(err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MemoryAddress(pub u32);
+
+type MemoryDatagram = (MemoryAddress, Vec<u8>);
+
+/// Connects [`MemoryTransport`] endpoints within a single process.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryNetwork {
+    endpoints: Arc<Mutex<HashMap<MemoryAddress, Sender<MemoryDatagram>>>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new endpoint with a unique address.
+    pub fn endpoint(&self) -> MemoryTransport {
+        let (sender, receiver) = channel();
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let address = MemoryAddress(endpoints.len() as u32);
+        endpoints.insert(address, sender);
+
+        MemoryTransport {
+            address,
+            network: self.clone(),
+            receiver,
+        }
+    }
+}
+
+/// A channel backed transport. Datagrams sent to unknown addresses are dropped, like UDP.
+#[derive(Debug)]
+pub struct MemoryTransport {
+    address: MemoryAddress,
+    network: MemoryNetwork,
+    receiver: Receiver<MemoryDatagram>,
+}
+
+impl MemoryTransport {
+    pub fn local_addr(&self) -> MemoryAddress {
+        self.address
+    }
+}
+
+impl DatagramTransport for MemoryTransport {
+    type Address = MemoryAddress;
+
+    fn send_to(&mut self, datagram: &[u8], address: MemoryAddress) -> io::Result<()> {
+        let endpoints = self.network.endpoints.lock().unwrap();
+        if let Some(sender) = endpoints.get(&address) {
+            let _ = sender.send((self.address, datagram.to_vec()));
+        }
+        Ok(())
+    }
+
+    fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<Option<(usize, MemoryAddress)>> {
+        match self.receiver.try_recv() {
+            Ok(received) => copy_datagram(received, buffer).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn recv_timeout(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<Option<(usize, MemoryAddress)>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(received) => copy_datagram(received, buffer).map(Some),
+            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => Ok(None),
+        }
+    }
+}
+
+/// Fails instead of truncating a datagram that does not fit in `buffer`.
+fn copy_datagram(
+    (address, datagram): MemoryDatagram,
+    buffer: &mut [u8],
+) -> io::Result<(usize, MemoryAddress)> {
+    let Some(target) = buffer.get_mut(..datagram.len()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "datagram of {} octets does not fit in a buffer of {}",
+                datagram.len(),
+                buffer.len()
+            ),
+        ));
+    };
+    target.copy_from_slice(&datagram);
+    Ok((datagram.len(), address))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::transport::{DatagramTransport, MemoryNetwork, UdpTransport};
+
+    #[test]
+    fn memory_send_and_poll() {
+        let network = MemoryNetwork::new();
+        let mut first = network.endpoint();
+        let mut second = network.endpoint();
+
+        first.send_to(&[0x01], second.local_addr()).unwrap();
+        first.send_to(&[0x02, 0x03], second.local_addr()).unwrap();
+
+        assert_eq!(
+            second.poll().unwrap(),
+            vec![
+                (first.local_addr(), vec![0x01]),
+                (first.local_addr(), vec![0x02, 0x03])
+            ]
+        );
+        assert!(second.poll().unwrap().is_empty());
+        assert!(first.poll().unwrap().is_empty());
+    }
+
+    #[test]
+    fn memory_refuses_to_truncate() {
+        let network = MemoryNetwork::new();
+        let mut first = network.endpoint();
+        let mut second = network.endpoint();
+
+        first.send_to(&[0x01, 0x02], second.local_addr()).unwrap();
+        assert!(second.recv_from(&mut [0u8; 1]).is_err());
+    }
+
+    #[test]
+    fn udp_poll_waits_for_a_datagram() {
+        let mut first = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let mut second = UdpTransport::bind("127.0.0.1:0").unwrap();
+
+        let started_at = Instant::now();
+        assert!(second
+            .poll_timeout(Duration::from_millis(20))
+            .unwrap()
+            .is_empty());
+        assert!(started_at.elapsed() >= Duration::from_millis(20));
+
+        first
+            .send_to(&[0x01], second.local_addr().unwrap())
+            .unwrap();
+        assert_eq!(
+            second.poll_timeout(Duration::from_secs(1)).unwrap(),
+            vec![(first.local_addr().unwrap(), vec![0x01])]
+        );
+        assert!(second.poll().unwrap().is_empty());
+    }
+
+    #[test]
+    fn udp_send_and_poll() {
+        let mut first = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let mut second = UdpTransport::bind("127.0.0.1:0").unwrap();
+
+        first
+            .send_to(&[0x01, 0x02], second.local_addr().unwrap())
+            .unwrap();
+
+        let mut buffer = [0u8; 16];
+        for _ in 0..100 {
+            if let Some((size, address)) = second.recv_from(&mut buffer).unwrap() {
+                assert_eq!(&buffer[..size], [0x01, 0x02]);
+                assert_eq!(address, first.local_addr().unwrap());
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("no datagram received");
+    }
+}